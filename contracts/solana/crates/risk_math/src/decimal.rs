@@ -0,0 +1,219 @@
+//! Fixed-point decimal arithmetic for USD valuations and risk factors
+
+use crate::{MathError, MathResult};
+
+/// Scale of a `Decimal`: 18 decimal places, the same as PRECISION
+pub const WAD: u128 = 1_000_000_000_000_000_000;
+const WAD_DECIMALS: u32 = 18;
+
+/// Direction to round a result that cannot be represented exactly.
+/// Callers pick whichever direction favours the protocol.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Rounding {
+    Down,
+    Up,
+}
+
+/// Unsigned fixed-point number with 18 decimal places backed by `u128`
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Decimal(u128);
+
+impl Decimal {
+    pub const ZERO: Decimal = Decimal(0);
+    pub const ONE: Decimal = Decimal(WAD);
+
+    /// Wrap a value already scaled by WAD, e.g. a stored `_usd` field or a PRECISION factor
+    pub const fn from_scaled_val(scaled_val: u128) -> Self {
+        Decimal(scaled_val)
+    }
+
+    pub const fn to_scaled_val(self) -> u128 {
+        self.0
+    }
+
+    pub fn from_integer(value: u64) -> Self {
+        // u64::MAX * WAD stays below u128::MAX
+        Decimal(value as u128 * WAD)
+    }
+
+    /// Token amount in whole tokens for a balance in base units
+    pub fn from_token_amount(amount: u64, decimals: u8) -> MathResult<Self> {
+        let scale = 10_u128
+            .checked_pow(WAD_DECIMALS.checked_sub(decimals as u32).ok_or(MathError::Overflow)?)
+            .ok_or(MathError::Overflow)?;
+        Ok(Decimal(amount as u128 * scale))
+    }
+
+    /// `numerator / denominator` as a decimal
+    pub fn from_ratio(numerator: u64, denominator: u64, rounding: Rounding) -> MathResult<Self> {
+        Decimal::from_integer(numerator).try_div(Decimal::from_integer(denominator), rounding)
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn try_add(self, rhs: Decimal) -> MathResult<Self> {
+        Ok(Decimal(self.0.checked_add(rhs.0).ok_or(MathError::Overflow)?))
+    }
+
+    pub fn try_sub(self, rhs: Decimal) -> MathResult<Self> {
+        Ok(Decimal(self.0.checked_sub(rhs.0).ok_or(MathError::Overflow)?))
+    }
+
+    pub fn saturating_sub(self, rhs: Decimal) -> Self {
+        Decimal(self.0.saturating_sub(rhs.0))
+    }
+
+    /// Product of two decimals. The operands are split into integer and fractional
+    /// parts so the intermediate products fit in `u128` whenever the result does.
+    pub fn try_mul(self, rhs: Decimal, rounding: Rounding) -> MathResult<Self> {
+        let (lhs_int, lhs_frac) = (self.0 / WAD, self.0 % WAD);
+        let (rhs_int, rhs_frac) = (rhs.0 / WAD, rhs.0 % WAD);
+        let frac_product = lhs_frac * rhs_frac; // Both below WAD, so below WAD^2
+
+        let mut product = lhs_int
+            .checked_mul(rhs_int)
+            .and_then(|x| x.checked_mul(WAD))
+            .and_then(|x| x.checked_add(lhs_int.checked_mul(rhs_frac)?))
+            .and_then(|x| x.checked_add(lhs_frac.checked_mul(rhs_int)?))
+            .and_then(|x| x.checked_add(frac_product / WAD))
+            .ok_or(MathError::Overflow)?;
+        if rounding == Rounding::Up && frac_product % WAD != 0 {
+            product = product.checked_add(1).ok_or(MathError::Overflow)?;
+        }
+        Ok(Decimal(product))
+    }
+
+    /// Quotient of two decimals. The fractional digits are produced by long division
+    /// when scaling the dividend by WAD would overflow.
+    pub fn try_div(self, rhs: Decimal, rounding: Rounding) -> MathResult<Self> {
+        if rhs.is_zero() {
+            return Err(MathError::DivisionByZero);
+        }
+
+        let (mut quotient, remainder) = match self.0.checked_mul(WAD) {
+            Some(scaled) => (scaled / rhs.0, scaled % rhs.0),
+            None => {
+                let mut quotient = (self.0 / rhs.0).checked_mul(WAD).ok_or(MathError::Overflow)?;
+                let mut remainder = self.0 % rhs.0;
+                let mut digit_scale = WAD;
+                while digit_scale > 1 && remainder != 0 {
+                    digit_scale /= 10;
+                    remainder = remainder.checked_mul(10).ok_or(MathError::Overflow)?;
+                    quotient = quotient
+                        .checked_add(remainder / rhs.0 * digit_scale)
+                        .ok_or(MathError::Overflow)?;
+                    remainder %= rhs.0;
+                }
+                (quotient, remainder)
+            }
+        };
+        if rounding == Rounding::Up && remainder != 0 {
+            quotient = quotient.checked_add(1).ok_or(MathError::Overflow)?;
+        }
+        Ok(Decimal(quotient))
+    }
+
+    pub fn try_mul_int(self, rhs: u64) -> MathResult<Self> {
+        Ok(Decimal(self.0.checked_mul(rhs as u128).ok_or(MathError::Overflow)?))
+    }
+
+    pub fn try_div_int(self, rhs: u64, rounding: Rounding) -> MathResult<Self> {
+        if rhs == 0 {
+            return Err(MathError::DivisionByZero);
+        }
+        let quotient = match rounding {
+            Rounding::Down => self.0 / rhs as u128,
+            Rounding::Up => self.0.div_ceil(rhs as u128),
+        };
+        Ok(Decimal(quotient))
+    }
+
+    /// Whole-number part as `u64`
+    pub fn try_to_u64(self, rounding: Rounding) -> MathResult<u64> {
+        let integer = match rounding {
+            Rounding::Down => self.0 / WAD,
+            Rounding::Up => self.0.div_ceil(WAD),
+        };
+        u64::try_from(integer).map_err(|_| MathError::Overflow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mul_keeps_intermediate_products_in_range() {
+        // 1 USDC is 1e6 base units, scaled by 1e12 to a WAD, times a price of 1e8
+        let one_usdc = Decimal::from_token_amount(1_000_000, 6).unwrap();
+        assert_eq!(one_usdc, Decimal::ONE);
+        let price = Decimal::from_integer(100_000_000);
+        assert_eq!(one_usdc.try_mul(price, Rounding::Down), Ok(price));
+
+        // The largest 6-decimal balance at $1 still fits
+        let max_usdc = Decimal::from_token_amount(u64::MAX, 6).unwrap();
+        assert_eq!(max_usdc.try_mul(Decimal::ONE, Rounding::Up), Ok(max_usdc));
+        assert_eq!(
+            Decimal::from_integer(u64::MAX).try_mul(Decimal::from_integer(u64::MAX), Rounding::Down),
+            Err(MathError::Overflow)
+        );
+    }
+
+    #[test]
+    fn mul_rounds_in_the_requested_direction() {
+        let smallest = Decimal::from_scaled_val(1);
+        assert_eq!(smallest.try_mul(smallest, Rounding::Down), Ok(Decimal::ZERO));
+        assert_eq!(smallest.try_mul(smallest, Rounding::Up), Ok(smallest));
+
+        // Exact products are unaffected by the rounding direction
+        let half = Decimal::from_scaled_val(WAD / 2);
+        assert_eq!(half.try_mul(half, Rounding::Up), Ok(Decimal::from_scaled_val(WAD / 4)));
+    }
+
+    #[test]
+    fn div_rounds_in_the_requested_direction() {
+        let third_down = Decimal::ONE.try_div(Decimal::from_integer(3), Rounding::Down).unwrap();
+        let third_up = Decimal::ONE.try_div(Decimal::from_integer(3), Rounding::Up).unwrap();
+        assert_eq!(third_down.to_scaled_val(), 333_333_333_333_333_333);
+        assert_eq!(third_up.to_scaled_val(), 333_333_333_333_333_334);
+        assert_eq!(
+            Decimal::ONE.try_div(Decimal::ZERO, Rounding::Down),
+            Err(MathError::DivisionByZero)
+        );
+    }
+
+    #[test]
+    fn div_falls_back_to_long_division() {
+        // The dividend times WAD overflows u128, so the fractional digits come from long division
+        let dividend = Decimal::from_integer(1_000_000_000_000);
+        assert!(dividend.to_scaled_val().checked_mul(WAD).is_none());
+
+        let down = dividend.try_div(Decimal::from_integer(3), Rounding::Down).unwrap();
+        let up = dividend.try_div(Decimal::from_integer(3), Rounding::Up).unwrap();
+        assert_eq!(down.to_scaled_val(), 333_333_333_333_333_333_333_333_333_333);
+        assert_eq!(up.to_scaled_val(), 333_333_333_333_333_333_333_333_333_334);
+
+        let quarter = dividend.try_div(Decimal::from_integer(4), Rounding::Up).unwrap();
+        assert_eq!(quarter, Decimal::from_integer(250_000_000_000));
+
+        // A fractional divisor takes the same path
+        let doubled = dividend.try_div(Decimal::from_scaled_val(WAD / 2), Rounding::Down).unwrap();
+        assert_eq!(doubled, Decimal::from_integer(2_000_000_000_000));
+    }
+
+    #[test]
+    fn to_u64_rounds_and_checks_range() {
+        let just_over_one = Decimal::from_scaled_val(WAD + 1);
+        assert_eq!(just_over_one.try_to_u64(Rounding::Down), Ok(1));
+        assert_eq!(just_over_one.try_to_u64(Rounding::Up), Ok(2));
+        assert_eq!(Decimal::from_integer(5).try_to_u64(Rounding::Up), Ok(5));
+
+        let max = Decimal::from_integer(u64::MAX);
+        assert_eq!(max.try_to_u64(Rounding::Down), Ok(u64::MAX));
+        let over_max = max.try_add(Decimal::from_scaled_val(1)).unwrap();
+        assert_eq!(over_max.try_to_u64(Rounding::Down), Ok(u64::MAX));
+        assert_eq!(over_max.try_to_u64(Rounding::Up), Err(MathError::Overflow));
+    }
+}
@@ -0,0 +1,36 @@
+//! Valuation and risk math shared by the lending pool program and off-chain tools.
+//! Keepers, the SDK and simulators link this crate so their health predictions match
+//! the program exactly. It has no Anchor or Solana dependency and builds without `std`.
+#![no_std]
+
+mod decimal;
+mod rate;
+mod risk;
+
+pub use decimal::{Decimal, Rounding, WAD};
+pub use rate::{mul_div, Rate, RAY};
+pub use risk::*;
+
+// Constants
+pub const PRECISION: u64 = 1_000_000_000_000_000_000; // 1e18
+pub const PRICE_DECIMALS: u32 = 8; // Prices are normalized to 8 decimals
+pub const CLOSE_FACTOR_HEALTH_THRESHOLD: u64 = 950_000_000_000_000_000; // 0.95 - full liquidation allowed below
+pub const DUST_DEBT_VALUE_USD: u128 = 10_000_000_000_000_000_000; // $10
+pub const DUTCH_AUCTION_START_BONUS: u64 = 5_000_000_000_000_000; // 0.005 (0.5%)
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MathError {
+    Overflow,
+    DivisionByZero,
+}
+
+impl core::fmt::Display for MathError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MathError::Overflow => f.write_str("Math overflow"),
+            MathError::DivisionByZero => f.write_str("Division by zero"),
+        }
+    }
+}
+
+pub type MathResult<T> = Result<T, MathError>;
@@ -0,0 +1,153 @@
+//! Ray-precision rates for interest accrual and the cumulative borrow index
+
+use crate::{MathError, MathResult, Rounding};
+
+/// Scale of a `Rate`: 27 decimal places, so per-second growth keeps its precision
+pub const RAY: u128 = 1_000_000_000_000_000_000_000_000_000;
+const WAD_TO_RAY: u128 = 1_000_000_000;
+
+/// Unsigned fixed-point rate with 27 decimal places backed by `u128`
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Rate(u128);
+
+impl Rate {
+    pub const ZERO: Rate = Rate(0);
+    pub const ONE: Rate = Rate(RAY);
+
+    /// Wrap a value already scaled by RAY, e.g. a stored borrow index
+    pub const fn from_scaled_val(scaled_val: u128) -> Self {
+        Rate(scaled_val)
+    }
+
+    pub const fn to_scaled_val(self) -> u128 {
+        self.0
+    }
+
+    /// Rate for a factor scaled by PRECISION, e.g. an annual borrow rate
+    pub fn from_wad(factor: u64) -> Self {
+        // u64::MAX * 1e9 stays below u128::MAX
+        Rate(factor as u128 * WAD_TO_RAY)
+    }
+
+    pub fn try_add(self, rhs: Rate) -> MathResult<Self> {
+        Ok(Rate(self.0.checked_add(rhs.0).ok_or(MathError::Overflow)?))
+    }
+
+    pub fn try_mul(self, rhs: Rate, rounding: Rounding) -> MathResult<Self> {
+        Ok(Rate(mul_div(self.0, rhs.0, RAY, rounding)?))
+    }
+
+    pub fn try_mul_int(self, rhs: u64) -> MathResult<Self> {
+        Ok(Rate(self.0.checked_mul(rhs as u128).ok_or(MathError::Overflow)?))
+    }
+
+    pub fn try_div_int(self, rhs: u64, rounding: Rounding) -> MathResult<Self> {
+        Ok(Rate(mul_div(self.0, 1, rhs as u128, rounding)?))
+    }
+
+    /// `amount * self` for a token amount
+    pub fn try_mul_amount(self, amount: u64, rounding: Rounding) -> MathResult<u64> {
+        to_u64(mul_div(amount as u128, self.0, RAY, rounding)?)
+    }
+
+    /// `amount / self` for a token amount
+    pub fn try_div_amount(self, amount: u64, rounding: Rounding) -> MathResult<u64> {
+        to_u64(mul_div(amount as u128, RAY, self.0, rounding)?)
+    }
+}
+
+fn to_u64(value: u128) -> MathResult<u64> {
+    u64::try_from(value).map_err(|_| MathError::Overflow)
+}
+
+/// `a * b / c` with a 256-bit intermediate product, so it only fails when the result
+/// itself does not fit in `u128`
+pub fn mul_div(a: u128, b: u128, c: u128, rounding: Rounding) -> MathResult<u128> {
+    if c == 0 {
+        return Err(MathError::DivisionByZero);
+    }
+    let (high, low) = widening_mul(a, b);
+    if high == 0 {
+        let quotient = match rounding {
+            Rounding::Down => low / c,
+            Rounding::Up => low.div_ceil(c),
+        };
+        return Ok(quotient);
+    }
+    if high >= c {
+        return Err(MathError::Overflow);
+    }
+
+    // Binary long division of high:low by c, one bit of low at a time
+    let mut remainder = high;
+    let mut quotient = 0_u128;
+    for bit in (0..128).rev() {
+        let carry = remainder >> 127;
+        remainder = (remainder << 1) | ((low >> bit) & 1);
+        if carry == 1 || remainder >= c {
+            remainder = remainder.wrapping_sub(c);
+            quotient |= 1 << bit;
+        }
+    }
+    if rounding == Rounding::Up && remainder != 0 {
+        quotient = quotient.checked_add(1).ok_or(MathError::Overflow)?;
+    }
+    Ok(quotient)
+}
+
+/// Full 256-bit product as (high, low) halves
+fn widening_mul(a: u128, b: u128) -> (u128, u128) {
+    const MASK: u128 = u64::MAX as u128;
+    let (a_high, a_low) = (a >> 64, a & MASK);
+    let (b_high, b_low) = (b >> 64, b & MASK);
+
+    let low_low = a_low * b_low;
+    let high_low = a_high * b_low;
+    let low_high = a_low * b_high;
+    let high_high = a_high * b_high;
+
+    let middle = (low_low >> 64) + (high_low & MASK) + (low_high & MASK);
+    let low = (middle << 64) | (low_low & MASK);
+    let high = high_high + (high_low >> 64) + (low_high >> 64) + (middle >> 64);
+    (high, low)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mul_div_uses_a_wide_intermediate() {
+        assert_eq!(mul_div(u128::MAX, u128::MAX, u128::MAX, Rounding::Down), Ok(u128::MAX));
+        assert_eq!(mul_div(u128::MAX, 2, 4, Rounding::Down), Ok((1 << 127) - 1));
+        assert_eq!(mul_div(u128::MAX, 2, 4, Rounding::Up), Ok(1 << 127));
+        assert_eq!(mul_div(u128::MAX, 2, 1, Rounding::Down), Err(MathError::Overflow));
+        assert_eq!(mul_div(1, 1, 0, Rounding::Down), Err(MathError::DivisionByZero));
+        assert_eq!(mul_div(1, 1, 2, Rounding::Down), Ok(0));
+        assert_eq!(mul_div(1, 1, 2, Rounding::Up), Ok(1));
+    }
+
+    #[test]
+    fn index_growth_and_debt_conversion() {
+        // 5% annual rate applied for a full year to a starting index of 1.0
+        let growth = Rate::from_wad(50_000_000_000_000_000);
+        let index = Rate::ONE.try_mul(Rate::ONE.try_add(growth).unwrap(), Rounding::Down).unwrap();
+        assert_eq!(index.to_scaled_val(), RAY + RAY / 20);
+
+        // Debt rounds up and the scaled balance rounds as asked
+        let index = Rate::from_scaled_val(3 * RAY);
+        assert_eq!(index.try_mul_amount(7, Rounding::Up), Ok(21));
+        assert_eq!(index.try_div_amount(10, Rounding::Down), Ok(3));
+        assert_eq!(index.try_div_amount(10, Rounding::Up), Ok(4));
+        assert_eq!(index.try_mul_amount(u64::MAX, Rounding::Down), Err(MathError::Overflow));
+    }
+
+    #[test]
+    fn per_second_rate_keeps_precision() {
+        // A 1% annual rate per second, which a WAD would truncate to 317_097_919
+        let per_second = Rate::from_wad(10_000_000_000_000_000)
+            .try_div_int(31_536_000, Rounding::Down)
+            .unwrap();
+        assert_eq!(per_second.to_scaled_val(), 317_097_919_837_645_865);
+    }
+}
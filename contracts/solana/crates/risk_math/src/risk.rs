@@ -0,0 +1,257 @@
+//! Position valuation, health factor and liquidation sizing
+
+use crate::{
+    Decimal, MathResult, Rounding, CLOSE_FACTOR_HEALTH_THRESHOLD, DUST_DEBT_VALUE_USD,
+    DUTCH_AUCTION_START_BONUS, PRECISION, PRICE_DECIMALS,
+};
+
+/// Aggregate USD values of an obligation. Collateral is weighted by LTV for borrowing
+/// power and by liquidation threshold for health.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct ObligationValues {
+    pub deposited_value_usd: Decimal,
+    pub borrowed_value_usd: Decimal,
+    pub allowed_borrow_value_usd: Decimal,
+    pub unhealthy_borrow_value_usd: Decimal,
+}
+
+impl ObligationValues {
+    /// Count collateral, rounding its weighted values down in the protocol's favour
+    pub fn add_collateral(&mut self, value_usd: Decimal, ltv: u64, liquidation_threshold: u64) -> MathResult<()> {
+        self.deposited_value_usd = self.deposited_value_usd.try_add(value_usd)?;
+        self.allowed_borrow_value_usd = self.allowed_borrow_value_usd
+            .try_add(weighted_value(value_usd, ltv, Rounding::Down)?)?;
+        self.unhealthy_borrow_value_usd = self.unhealthy_borrow_value_usd
+            .try_add(weighted_value(value_usd, liquidation_threshold, Rounding::Down)?)?;
+        Ok(())
+    }
+
+    /// Take collateral out, rounding its weighted values up in the protocol's favour
+    pub fn remove_collateral(&mut self, value_usd: Decimal, ltv: u64, liquidation_threshold: u64) -> MathResult<()> {
+        self.deposited_value_usd = self.deposited_value_usd.saturating_sub(value_usd);
+        self.allowed_borrow_value_usd = self.allowed_borrow_value_usd
+            .saturating_sub(weighted_value(value_usd, ltv, Rounding::Up)?);
+        self.unhealthy_borrow_value_usd = self.unhealthy_borrow_value_usd
+            .saturating_sub(weighted_value(value_usd, liquidation_threshold, Rounding::Up)?);
+        Ok(())
+    }
+
+    pub fn add_borrow(&mut self, value_usd: Decimal) -> MathResult<()> {
+        self.borrowed_value_usd = self.borrowed_value_usd.try_add(value_usd)?;
+        Ok(())
+    }
+
+    pub fn remove_borrow(&mut self, value_usd: Decimal) {
+        self.borrowed_value_usd = self.borrowed_value_usd.saturating_sub(value_usd);
+    }
+
+    /// Whether `value_usd` more debt stays within the LTV-weighted collateral
+    pub fn can_borrow(&self, value_usd: Decimal) -> MathResult<bool> {
+        Ok(self.borrowed_value_usd.try_add(value_usd)? <= self.allowed_borrow_value_usd)
+    }
+
+    pub fn health_factor(&self) -> MathResult<u64> {
+        // Collateral is already weighted by its liquidation threshold
+        calculate_health_factor(self.unhealthy_borrow_value_usd, self.borrowed_value_usd, PRECISION)
+    }
+}
+
+/// Collateral value scaled by an LTV or liquidation threshold
+pub fn weighted_value(value_usd: Decimal, factor: u64, rounding: Rounding) -> MathResult<Decimal> {
+    value_usd.try_mul(Decimal::from_scaled_val(factor.into()), rounding)
+}
+
+/// Share `part / whole` of a value, e.g. the value of some shares of a deposit
+pub fn pro_rata_value(value_usd: Decimal, part: u64, whole: u64, rounding: Rounding) -> MathResult<Decimal> {
+    Ok(value_usd
+        .try_mul(Decimal::from_ratio(part, whole, rounding)?, rounding)?
+        .min(value_usd))
+}
+
+/// Whether seizing threshold-weighted collateral plus the bonus stays within the collateral itself
+pub fn liquidation_bonus_is_covered(liquidation_threshold: u64, liquidation_bonus: u64) -> MathResult<bool> {
+    let payout = Decimal::from_scaled_val(liquidation_threshold.into()).try_mul(
+        Decimal::ONE.try_add(Decimal::from_scaled_val(liquidation_bonus.into()))?,
+        Rounding::Up,
+    )?;
+    Ok(payout <= Decimal::ONE)
+}
+
+/// USD value of a token amount at a price with PRICE_DECIMALS decimals
+pub fn calculate_usd_value(amount: u64, price: u64, decimals: u8, rounding: Rounding) -> MathResult<Decimal> {
+    let price = Decimal::from_integer(price).try_div_int(10_u64.pow(PRICE_DECIMALS), rounding)?;
+    Decimal::from_token_amount(amount, decimals)?.try_mul(price, rounding)
+}
+
+/// Threshold-weighted collateral over debt, scaled by PRECISION and rounded down.
+/// Saturates at u64::MAX, which also stands for an obligation without debt.
+pub fn calculate_health_factor(
+    total_collateral_value_usd: Decimal,
+    total_borrow_value_usd: Decimal,
+    liquidation_threshold: u64,
+) -> MathResult<u64> {
+    if total_borrow_value_usd.is_zero() {
+        return Ok(u64::MAX); // No debt means infinite health factor
+    }
+
+    let adjusted_collateral = weighted_value(total_collateral_value_usd, liquidation_threshold, Rounding::Down)?;
+    let health_factor = adjusted_collateral.try_div(total_borrow_value_usd, Rounding::Down)?;
+    Ok(u64::try_from(health_factor.to_scaled_val()).unwrap_or(u64::MAX))
+}
+
+/// Collateral owed for repaying `debt_amount`, converted between the two mints' decimals
+/// and rounded down in the protocol's favour
+pub fn calculate_liquidation_amount(
+    debt_amount: u64,
+    debt_decimals: u8,
+    debt_price: u64,
+    collateral_decimals: u8,
+    collateral_price: u64,
+    liquidation_bonus: u64,
+) -> MathResult<u64> {
+    let debt_value = calculate_usd_value(debt_amount, debt_price, debt_decimals, Rounding::Down)?;
+    let collateral_value_needed = debt_value.try_mul(
+        Decimal::ONE.try_add(Decimal::from_scaled_val(liquidation_bonus.into()))?,
+        Rounding::Down,
+    )?;
+
+    // Whole collateral tokens at the collateral price, then base units
+    let collateral_price = Decimal::from_integer(collateral_price)
+        .try_div_int(10_u64.pow(PRICE_DECIMALS), Rounding::Up)?;
+    collateral_value_needed
+        .try_div(collateral_price, Rounding::Down)?
+        .try_mul_int(10_u64.pow(collateral_decimals as u32))?
+        .try_to_u64(Rounding::Down)
+}
+
+/// Liquidation bonus rising linearly from DUTCH_AUCTION_START_BONUS to `max_bonus` over `auction_slots`
+pub fn dutch_auction_bonus(
+    max_bonus: u64,
+    liquidatable_since_slot: u64,
+    current_slot: u64,
+    auction_slots: u64,
+) -> u64 {
+    let start_bonus = DUTCH_AUCTION_START_BONUS.min(max_bonus);
+    let elapsed_slots = current_slot.saturating_sub(liquidatable_since_slot);
+    if elapsed_slots >= auction_slots {
+        return max_bonus;
+    }
+    start_bonus + ((max_bonus - start_bonus) as u128 * elapsed_slots as u128 / auction_slots as u128) as u64
+}
+
+/// Largest repayment one liquidation may make against a borrow. The whole borrow may be
+/// repaid below CLOSE_FACTOR_HEALTH_THRESHOLD or when the close factor would leave only dust.
+pub fn max_liquidation_amount(
+    current_debt: u64,
+    debt_value_usd: Decimal,
+    close_factor: u64,
+    health_factor: u64,
+) -> MathResult<u64> {
+    let remaining_value_usd = weighted_value(debt_value_usd, PRECISION - close_factor, Rounding::Down)?;
+    if health_factor < CLOSE_FACTOR_HEALTH_THRESHOLD
+        || remaining_value_usd < Decimal::from_scaled_val(DUST_DEBT_VALUE_USD)
+    {
+        return Ok(current_debt);
+    }
+    weighted_value(Decimal::from_integer(current_debt), close_factor, Rounding::Down)?.try_to_u64(Rounding::Down)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WAD;
+
+    const USD: u64 = 100_000_000; // $1 at PRICE_DECIMALS
+
+    #[test]
+    fn liquidation_amount_converts_between_mint_decimals() {
+        // 100 USDC (6 decimals) repaid for SOL (9 decimals) at $100 with a 5% bonus
+        let bonus = 50_000_000_000_000_000;
+        assert_eq!(calculate_liquidation_amount(100_000_000, 6, USD, 9, 100 * USD, bonus), Ok(1_050_000_000));
+
+        // 1 SOL repaid for USDC without a bonus
+        assert_eq!(calculate_liquidation_amount(1_000_000_000, 9, 100 * USD, 6, USD, 0), Ok(100_000_000));
+
+        // A tenth of a USDC base unit rounds down to nothing
+        assert_eq!(calculate_liquidation_amount(1, 9, 100 * USD, 6, USD, 0), Ok(0));
+    }
+
+    #[test]
+    fn max_liquidation_amount_applies_the_close_factor() {
+        let half = PRECISION / 2;
+        let healthy_enough = CLOSE_FACTOR_HEALTH_THRESHOLD;
+        let debt_value = Decimal::from_integer(1_000);
+        assert_eq!(max_liquidation_amount(1_000_000_000, debt_value, half, healthy_enough), Ok(500_000_000));
+    }
+
+    #[test]
+    fn max_liquidation_amount_allows_full_repayment_of_dust() {
+        // Half of a $15 borrow would leave $7.50, below DUST_DEBT_VALUE_USD
+        let debt_value = Decimal::from_integer(15);
+        assert_eq!(
+            max_liquidation_amount(15_000_000, debt_value, PRECISION / 2, CLOSE_FACTOR_HEALTH_THRESHOLD),
+            Ok(15_000_000)
+        );
+    }
+
+    #[test]
+    fn max_liquidation_amount_allows_full_repayment_when_deeply_unhealthy() {
+        let debt_value = Decimal::from_integer(1_000);
+        assert_eq!(
+            max_liquidation_amount(1_000_000_000, debt_value, PRECISION / 2, CLOSE_FACTOR_HEALTH_THRESHOLD - 1),
+            Ok(1_000_000_000)
+        );
+    }
+
+    #[test]
+    fn dutch_auction_bonus_endpoints() {
+        let max_bonus = 100_000_000_000_000_000; // 10%
+        assert_eq!(dutch_auction_bonus(max_bonus, 100, 100, 50), DUTCH_AUCTION_START_BONUS);
+        assert_eq!(dutch_auction_bonus(max_bonus, 100, 150, 50), max_bonus);
+        assert_eq!(dutch_auction_bonus(max_bonus, 100, 1_000, 50), max_bonus);
+        assert_eq!(
+            dutch_auction_bonus(max_bonus, 100, 125, 50),
+            (DUTCH_AUCTION_START_BONUS + max_bonus) / 2
+        );
+
+        // A maximum below the starting bonus is paid from the first slot
+        assert_eq!(dutch_auction_bonus(1_000, 100, 100, 50), 1_000);
+    }
+
+    #[test]
+    fn obligation_values_round_in_the_protocols_favour() {
+        let half = PRECISION / 2;
+        let smallest = Decimal::from_scaled_val(1);
+
+        // Adding collateral rounds the weighted values down
+        let mut values = ObligationValues::default();
+        values.add_collateral(smallest, half, half).unwrap();
+        assert_eq!(values.deposited_value_usd, smallest);
+        assert_eq!(values.allowed_borrow_value_usd, Decimal::ZERO);
+        assert_eq!(values.unhealthy_borrow_value_usd, Decimal::ZERO);
+
+        // Removing it rounds them up, saturating at zero
+        let mut values = ObligationValues {
+            allowed_borrow_value_usd: Decimal::from_scaled_val(10),
+            ..ObligationValues::default()
+        };
+        values.remove_collateral(smallest, half, half).unwrap();
+        assert_eq!(values.allowed_borrow_value_usd, Decimal::from_scaled_val(9));
+        assert_eq!(values.unhealthy_borrow_value_usd, Decimal::ZERO);
+        assert_eq!(values.deposited_value_usd, Decimal::ZERO);
+    }
+
+    #[test]
+    fn obligation_values_health_factor_rounds_down() {
+        let mut values = ObligationValues::default();
+        assert_eq!(values.health_factor(), Ok(u64::MAX));
+
+        values.add_collateral(Decimal::from_integer(1), PRECISION / 2, PRECISION).unwrap();
+        values.add_borrow(Decimal::from_integer(3)).unwrap();
+        assert_eq!(values.health_factor(), Ok(333_333_333_333_333_333));
+        assert!(values.can_borrow(Decimal::ZERO).is_ok_and(|allowed| !allowed));
+
+        values.remove_borrow(Decimal::from_integer(3));
+        assert!(values.can_borrow(Decimal::from_scaled_val(WAD / 2)).is_ok_and(|allowed| allowed));
+    }
+}
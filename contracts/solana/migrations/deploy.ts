@@ -34,7 +34,7 @@ async function main() {
     // Initialize the lending pool
     console.log("\n🏦 Initializing Lending Pool...");
     const initTx = await program.methods
-      .initialize(admin, ccipProgram)
+      .initialize(admin, ccipProgram, admin)
       .accounts({
         pool: poolAccount,
         admin: admin,
@@ -1,9 +1,14 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Mint, Token, TokenAccount, Transfer};
+use anchor_lang::solana_program::instruction::{get_stack_height, Instruction, TRANSACTION_LEVEL_STACK_HEIGHT};
+use anchor_lang::solana_program::sysvar::instructions::{load_current_index_checked, load_instruction_at_checked};
+use anchor_spl::token::{self, Burn, CloseAccount, Mint, MintTo, Token, TokenAccount, Transfer};
+use risk_math::{
+    calculate_liquidation_amount, calculate_usd_value, dutch_auction_bonus, liquidation_bonus_is_covered,
+    max_liquidation_amount, mul_div, pro_rata_value, Decimal, MathError, ObligationValues, Rate, Rounding, RAY,
+};
 // Remove unused import
 // use anchor_spl::associated_token::AssociatedToken;
-// Remove chainlink import until compatible version is available
-// use chainlink_solana as chainlink;
+// Chainlink OCR2 feeds are decoded directly by ChainlinkAdapter, no chainlink_solana import needed
 
 declare_id!("ss9Hb9bSa6jW2w3UUNBN2aGviAUVMmnwVZ71HZw6xBL");
 
@@ -32,20 +37,140 @@ pub enum ErrorCode {
     PositionNotFound,
     #[msg("Chain not supported")]
     ChainNotSupported,
+    #[msg("Oracle sources deviate beyond the configured threshold")]
+    PriceDeviationTooHigh,
+    #[msg("Secondary price feed missing")]
+    SecondaryPriceFeedMissing,
+    #[msg("Price history missing or stale")]
+    PriceHistoryUnavailable,
+    #[msg("Price feed does not match the asset's configured oracle")]
+    PriceFeedMismatch,
+    #[msg("Invalid interest rate model")]
+    InvalidInterestRateModel,
+    #[msg("Invalid reserve factor")]
+    InvalidReserveFactor,
+    #[msg("Flash loan is not repaid later in the same transaction")]
+    FlashLoanNotRepaid,
+    #[msg("Invalid flash loan instruction sequence")]
+    InvalidFlashLoan,
+    #[msg("Insufficient liquidity")]
+    InsufficientLiquidity,
+    #[msg("Supply cap exceeded")]
+    SupplyCapExceeded,
+    #[msg("Borrow cap exceeded")]
+    BorrowCapExceeded,
+    #[msg("Asset parameters exceed its risk tier limits")]
+    RiskTierLimitExceeded,
+    #[msg("Asset cannot be borrowed against isolated collateral")]
+    IsolationModeBorrowNotAllowed,
+    #[msg("Isolated collateral debt ceiling exceeded")]
+    DebtCeilingExceeded,
+    #[msg("Invalid e-mode category")]
+    InvalidEModeCategory,
+    #[msg("Asset is not in the position's e-mode category")]
+    EModeCategoryMismatch,
+    #[msg("Reserve or oracle account missing for an obligation entry")]
+    MissingReserveAccount,
+    #[msg("Obligation already holds the maximum number of reserves")]
+    ObligationReserveLimit,
+    #[msg("Isolated collateral cannot be combined with other collateral")]
+    IsolatedCollateralConflict,
+    #[msg("Isolated collateral reserve missing or mismatched")]
+    IsolatedReserveMismatch,
+    #[msg("Obligation must be refreshed in the current slot")]
+    ObligationStale,
+    #[msg("Invalid close factor")]
+    InvalidCloseFactor,
+    #[msg("Repayment exceeds the close factor")]
+    LiquidationTooLarge,
+    #[msg("Invalid liquidation bonus or protocol liquidation fee")]
+    InvalidLiquidationBonus,
+    #[msg("Obligation still has collateral")]
+    ObligationHasCollateral,
+    #[msg("Math overflow")]
+    MathOverflow,
+    #[msg("LTV must be below the liquidation threshold, which may not exceed 1.0")]
+    InvalidRiskParameters,
+    #[msg("Asset is being delisted")]
+    AssetDelisted,
+    #[msg("Delisted asset still has deposits, borrows or protocol reserves")]
+    AssetNotWoundDown,
+    #[msg("Obligation is in e-mode but its category account is missing")]
+    EModeCategoryMissing,
+    #[msg("Reserve has outstanding shares but no deposits left after a bad debt write-off")]
+    ReserveInsolvent,
+}
+
+impl From<MathError> for ErrorCode {
+    fn from(_: MathError) -> Self {
+        ErrorCode::MathOverflow
+    }
 }
 
 // Constants
-pub const PRECISION: u64 = 1_000_000_000_000_000_000; // 1e18
+pub use risk_math::{CLOSE_FACTOR_HEALTH_THRESHOLD, DUST_DEBT_VALUE_USD, DUTCH_AUCTION_START_BONUS, PRECISION};
 pub const MIN_HEALTH_FACTOR: u64 = PRECISION; // 1.0
 pub const LIQUIDATION_THRESHOLD: u64 = 950_000_000_000_000_000; // 0.95
-pub const LIQUIDATION_BONUS: u64 = 50_000_000_000_000_000; // 0.05 (5%)
 pub const MAX_LTV: u64 = 750_000_000_000_000_000; // 0.75 (75%)
-
-// Bonk-specific constants
-pub const BONK_MINT: &str = "DezXAZ8z7PnrnRJjz3wXBoRgixCa6xjnB7YaB1pPB263";
-pub const BONK_DECIMALS: u8 = 5;
-pub const BONK_LTV: u64 = 600_000_000_000_000_000; // 0.60 (60%) - Lower LTV for meme tokens
-pub const BONK_LIQUIDATION_THRESHOLD: u64 = 800_000_000_000_000_000; // 0.80 (80%)
+pub const SECONDS_PER_YEAR: u64 = 31_536_000;
+pub const INITIAL_BORROW_INDEX: u128 = RAY; // 1.0 as a Rate
+
+// Obligation limits
+pub const MAX_OBLIGATION_DEPOSITS: usize = 8;
+pub const MAX_OBLIGATION_BORROWS: usize = 5;
+
+// Risk tier limits
+pub const STABLE_MAX_LTV: u64 = 850_000_000_000_000_000; // 0.85 (85%)
+pub const STABLE_MAX_LIQUIDATION_THRESHOLD: u64 = 900_000_000_000_000_000; // 0.90 (90%)
+pub const STANDARD_MAX_LTV: u64 = MAX_LTV; // 0.75 (75%)
+pub const STANDARD_MAX_LIQUIDATION_THRESHOLD: u64 = 850_000_000_000_000_000; // 0.85 (85%)
+pub const VOLATILE_MAX_LTV: u64 = 600_000_000_000_000_000; // 0.60 (60%) - Lower LTV for meme tokens
+pub const VOLATILE_MAX_LIQUIDATION_THRESHOLD: u64 = 800_000_000_000_000_000; // 0.80 (80%)
+
+// Oracle constants
+pub use risk_math::PRICE_DECIMALS; // Prices are normalized to 8 decimals
+pub const MAX_PRICE_AGE_SECONDS: i64 = 60;
+pub const MAX_PRICE_CONFIDENCE_BPS: u64 = 200; // 2% of price
+pub const BPS_DENOMINATOR: u64 = 10_000;
+
+// Price history constants
+pub const PRICE_HISTORY_CAPACITY: usize = 24;
+pub const MIN_PRICE_SAMPLE_INTERVAL_SECONDS: i64 = 60;
+pub const MAX_PRICE_HISTORY_AGE_SECONDS: i64 = 900; // 15 minutes
+pub const EMA_PERIOD_SECONDS: i64 = 3_600; // 1 hour
+
+// Pyth price account layout (v2)
+pub const PYTH_MAGIC: u32 = 0xa1b2_c3d4;
+pub const PYTH_ACCOUNT_TYPE_PRICE: u32 = 3;
+pub const PYTH_STATUS_TRADING: u32 = 1;
+const PYTH_MAGIC_OFFSET: usize = 0;
+const PYTH_ACCOUNT_TYPE_OFFSET: usize = 8;
+const PYTH_EXPONENT_OFFSET: usize = 20;
+const PYTH_TIMESTAMP_OFFSET: usize = 96;
+const PYTH_AGG_PRICE_OFFSET: usize = 208;
+const PYTH_AGG_CONF_OFFSET: usize = 216;
+const PYTH_AGG_STATUS_OFFSET: usize = 224;
+const PYTH_AGG_PUB_SLOT_OFFSET: usize = 232;
+const PYTH_PRICE_ACCOUNT_MIN_LEN: usize = 240;
+
+// Chainlink OCR2 store transmissions account layout
+const CHAINLINK_DECIMALS_OFFSET: usize = 138;
+const CHAINLINK_LIVE_LENGTH_OFFSET: usize = 148;
+const CHAINLINK_LIVE_CURSOR_OFFSET: usize = 152;
+const CHAINLINK_TRANSMISSIONS_OFFSET: usize = 200; // 8 byte discriminator + 192 byte header
+const CHAINLINK_TRANSMISSION_SIZE: usize = 48;
+const CHAINLINK_TRANSMISSION_SLOT_OFFSET: usize = 0;
+const CHAINLINK_TRANSMISSION_TIMESTAMP_OFFSET: usize = 8;
+const CHAINLINK_TRANSMISSION_ANSWER_OFFSET: usize = 16;
+
+// Switchboard V2 aggregator account layout
+const SWITCHBOARD_ROUND_OFFSET: usize = 341; // latest_confirmed_round
+const SWITCHBOARD_NUM_SUCCESS_OFFSET: usize = SWITCHBOARD_ROUND_OFFSET;
+const SWITCHBOARD_ROUND_OPEN_SLOT_OFFSET: usize = SWITCHBOARD_ROUND_OFFSET + 9;
+const SWITCHBOARD_ROUND_OPEN_TIMESTAMP_OFFSET: usize = SWITCHBOARD_ROUND_OFFSET + 17;
+const SWITCHBOARD_RESULT_OFFSET: usize = SWITCHBOARD_ROUND_OFFSET + 25;
+const SWITCHBOARD_STD_DEVIATION_OFFSET: usize = SWITCHBOARD_ROUND_OFFSET + 45;
+const SWITCHBOARD_AGGREGATOR_MIN_LEN: usize = SWITCHBOARD_STD_DEVIATION_OFFSET + 20;
 
 #[program]
 pub mod lending_pool {
@@ -56,10 +181,12 @@ pub mod lending_pool {
         ctx: Context<Initialize>,
         admin: Pubkey,
         ccip_program: Pubkey,
+        treasury: Pubkey,
     ) -> Result<()> {
         let pool = &mut ctx.accounts.pool;
         pool.admin = admin;
         pool.ccip_program = ccip_program;
+        pool.treasury = treasury;
         pool.is_paused = false;
         pool.total_assets = 0;
         pool.bump = ctx.bumps.pool;
@@ -74,17 +201,53 @@ pub mod lending_pool {
         asset_config: AssetConfig,
     ) -> Result<()> {
         require!(!ctx.accounts.pool.is_paused, ErrorCode::NotAuthorized);
+        asset_config.params().validate(asset_config.risk_tier)?;
 
         let asset_info = &mut ctx.accounts.asset_info;
         asset_info.mint = ctx.accounts.mint.key();
+        asset_info.decimals = ctx.accounts.mint.decimals;
         asset_info.price_feed = asset_config.price_feed;
+        asset_info.oracle_source = asset_config.oracle_source;
+        asset_info.oracle_program = asset_config.oracle_program;
+        asset_info.secondary_price_feed = asset_config.secondary_price_feed;
+        asset_info.secondary_oracle_source = asset_config.secondary_oracle_source;
+        asset_info.secondary_oracle_program = asset_config.secondary_oracle_program;
+        asset_info.price_aggregation = asset_config.price_aggregation;
+        asset_info.max_price_deviation_bps = asset_config.max_price_deviation_bps;
+        asset_info.use_twap = asset_config.use_twap;
         asset_info.ltv = asset_config.ltv;
         asset_info.liquidation_threshold = asset_config.liquidation_threshold;
+        asset_info.liquidation_bonus = asset_config.liquidation_bonus;
+        asset_info.protocol_liquidation_fee = asset_config.protocol_liquidation_fee;
+        asset_info.liquidation_auction_slots = asset_config.liquidation_auction_slots;
         asset_info.is_active = true;
         asset_info.can_be_collateral = asset_config.can_be_collateral;
         asset_info.can_be_borrowed = asset_config.can_be_borrowed;
+        asset_info.share_mint = ctx.accounts.share_mint.key();
         asset_info.total_deposits = 0;
         asset_info.total_borrows = 0;
+        asset_info.total_scaled_borrows = 0;
+        asset_info.total_shares = 0;
+        asset_info.interest_rate_model = asset_config.interest_rate_model;
+        asset_info.reserve_factor = asset_config.reserve_factor;
+        asset_info.flash_loan_fee = asset_config.flash_loan_fee;
+        asset_info.supply_cap = asset_config.supply_cap;
+        asset_info.borrow_cap = asset_config.borrow_cap;
+        asset_info.risk_tier = asset_config.risk_tier;
+        asset_info.min_deposit_amount = asset_config.min_deposit_amount;
+        asset_info.min_borrow_amount = asset_config.min_borrow_amount;
+        asset_info.isolated = asset_config.isolated;
+        asset_info.debt_ceiling_usd = asset_config.debt_ceiling_usd;
+        asset_info.borrowable_in_isolation = asset_config.borrowable_in_isolation;
+        asset_info.isolated_debt_usd = 0;
+        asset_info.emode_category = asset_config.emode_category;
+        asset_info.close_factor = asset_config.close_factor;
+        asset_info.delist_timestamp = 0;
+        asset_info.delist_wind_down_seconds = 0;
+        asset_info.delist_liquidation_threshold = 0;
+        asset_info.protocol_reserves = 0;
+        asset_info.cumulative_borrow_index = INITIAL_BORROW_INDEX;
+        asset_info.last_accrual_timestamp = Clock::get()?.unix_timestamp;
         asset_info.bump = ctx.bumps.asset_info;
 
         let pool = &mut ctx.accounts.pool;
@@ -99,25 +262,127 @@ pub mod lending_pool {
         Ok(())
     }
 
-    /// Deposit collateral
+    /// Change any subset of a listed reserve's parameters (admin only)
+    pub fn update_asset_config(
+        ctx: Context<UpdateAssetConfig>,
+        update: AssetConfigUpdate,
+    ) -> Result<()> {
+        // Settle interest under the current rate model and reserve factor
+        let asset_info = &mut ctx.accounts.asset_info;
+        require!(!asset_info.is_delisted(), ErrorCode::AssetDelisted);
+        asset_info.accrue_interest(Clock::get()?.unix_timestamp)?;
+
+        let old_params = asset_info.params();
+        let new_params = update.apply(&old_params);
+        new_params.validate(asset_info.risk_tier)?;
+        asset_info.set_params(&new_params);
+
+        emit!(AssetConfigUpdatedEvent {
+            mint: ctx.accounts.mint.key(),
+            admin: ctx.accounts.admin.key(),
+            old_params,
+            new_params,
+        });
+
+        Ok(())
+    }
+
+    /// Start retiring a reserve (admin only). New deposits, borrows and flash loans stop,
+    /// its LTV drops to zero and its liquidation threshold falls linearly to zero over
+    /// `wind_down_seconds` so remaining positions are repaid, withdrawn or liquidated.
+    /// Only the threshold is ramped: LTV gates new borrows alone, so cutting it at once
+    /// cannot make an existing position liquidatable.
+    pub fn delist_asset(ctx: Context<UpdateAssetConfig>, wind_down_seconds: i64) -> Result<()> {
+        require!(wind_down_seconds > 0, ErrorCode::InvalidAmount);
+
+        let current_time = Clock::get()?.unix_timestamp;
+        let asset_info = &mut ctx.accounts.asset_info;
+        require!(!asset_info.is_delisted(), ErrorCode::AssetDelisted);
+        asset_info.accrue_interest(current_time)?;
+
+        asset_info.is_active = false;
+        asset_info.ltv = 0;
+        asset_info.delist_timestamp = current_time;
+        asset_info.delist_wind_down_seconds = wind_down_seconds;
+        asset_info.delist_liquidation_threshold = asset_info.liquidation_threshold;
+
+        emit!(AssetDelistedEvent {
+            mint: ctx.accounts.mint.key(),
+            liquidation_threshold: asset_info.liquidation_threshold,
+            wind_down_seconds,
+        });
+
+        Ok(())
+    }
+
+    /// Close a delisted reserve once every deposit and borrow is gone (admin only). Protocol
+    /// reserves and rounding dust left in the reserve are swept to the treasury.
+    pub fn close_delisted_asset(ctx: Context<CloseDelistedAsset>) -> Result<()> {
+        ctx.accounts.asset_info.accrue_interest(Clock::get()?.unix_timestamp)?;
+        let asset_info = &ctx.accounts.asset_info;
+        require!(asset_info.is_delisted(), ErrorCode::AssetNotSupported);
+        // Scaled balances are exact, unlike total_borrows which can round to zero early
+        require!(
+            asset_info.total_shares == 0 && asset_info.total_scaled_borrows == 0,
+            ErrorCode::AssetNotWoundDown
+        );
+
+        let pool_bump = ctx.accounts.pool.bump;
+        let signer_seeds: &[&[&[u8]]] = &[&[b"pool", &[pool_bump]]];
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+
+        let swept_amount = asset_info.protocol_reserves
+            .saturating_add(asset_info.total_deposits)
+            .min(ctx.accounts.pool_token_account.amount);
+        if swept_amount > 0 {
+            let cpi_accounts = Transfer {
+                from: ctx.accounts.pool_token_account.to_account_info(),
+                to: ctx.accounts.treasury_token_account.to_account_info(),
+                authority: ctx.accounts.pool.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program.clone(), cpi_accounts, signer_seeds);
+            token::transfer(cpi_ctx, swept_amount)?;
+        }
+
+        // The share mint stays behind with zero supply for a later re-listing
+        let cpi_accounts = CloseAccount {
+            account: ctx.accounts.collateral_escrow.to_account_info(),
+            destination: ctx.accounts.admin.to_account_info(),
+            authority: ctx.accounts.pool.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer_seeds);
+        token::close_account(cpi_ctx)?;
+
+        let pool = &mut ctx.accounts.pool;
+        pool.total_assets = pool.total_assets.checked_sub(1).unwrap();
+
+        emit!(AssetClosedEvent {
+            mint: ctx.accounts.mint.key(),
+            treasury: ctx.accounts.treasury_token_account.key(),
+            swept_amount,
+        });
+
+        Ok(())
+    }
+
+    /// Supply liquidity for transferable receipt shares, which back borrows once locked as collateral
     pub fn deposit(ctx: Context<DepositAccounts>, amount: u64) -> Result<()> {
         require!(amount > 0, ErrorCode::InvalidAmount);
         require!(!ctx.accounts.pool.is_paused, ErrorCode::NotAuthorized);
         require!(ctx.accounts.asset_info.is_active, ErrorCode::AssetNotSupported);
-        require!(ctx.accounts.asset_info.can_be_collateral, ErrorCode::AssetNotSupported);
 
-        // Special handling for Bonk token
-        if is_bonk_token(&ctx.accounts.mint.key()) {
-            // Additional validation for Bonk deposits
-            require!(amount >= 1000, ErrorCode::InvalidAmount); // Minimum deposit amount for Bonk
-        }
+        require!(amount >= ctx.accounts.asset_info.min_deposit_amount, ErrorCode::InvalidAmount);
 
-        // Rate limiting check
-        let user_position = &mut ctx.accounts.user_position;
         let current_time = Clock::get()?.unix_timestamp;
-        if user_position.last_action_timestamp + 900 > current_time { // 15 minutes
-            return Err(ErrorCode::RateLimited.into());
-        }
+        ctx.accounts.asset_info.accrue_interest(current_time)?;
+
+        // Enforce the supply cap
+        let asset_info = &ctx.accounts.asset_info;
+        require!(
+            asset_info.supply_cap == 0
+                || asset_info.total_deposits.checked_add(amount).unwrap() <= asset_info.supply_cap,
+            ErrorCode::SupplyCapExceeded
+        );
 
         // Transfer tokens from user to pool
         let cpi_accounts = Transfer {
@@ -129,103 +394,226 @@ pub mod lending_pool {
         let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
         token::transfer(cpi_ctx, amount)?;
 
-        // Update user position
-        if user_position.user == Pubkey::default() {
-            user_position.user = ctx.accounts.user.key();
-            user_position.bump = ctx.bumps.user_position;
-        }
+        // Mint receipt shares at the current exchange rate to the user
+        let shares = ctx.accounts.asset_info.amount_to_shares(amount, Rounding::Down)?;
+        require!(shares > 0, ErrorCode::InvalidAmount);
 
-        user_position.collateral_balance = user_position.collateral_balance
-            .checked_add(amount)
-            .unwrap();
-        user_position.last_action_timestamp = current_time;
+        let pool_bump = ctx.accounts.pool.bump;
+        let signer_seeds: &[&[&[u8]]] = &[&[b"pool", &[pool_bump]]];
+
+        let cpi_accounts = MintTo {
+            mint: ctx.accounts.share_mint.to_account_info(),
+            to: ctx.accounts.user_share_account.to_account_info(),
+            authority: ctx.accounts.pool.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer_seeds);
+        token::mint_to(cpi_ctx, shares)?;
 
         // Update asset info
         let asset_info = &mut ctx.accounts.asset_info;
         asset_info.total_deposits = asset_info.total_deposits
             .checked_add(amount)
             .unwrap();
-
-        // Update health factor
-        update_health_factor(user_position, &ctx.remaining_accounts)?;
+        asset_info.total_shares = asset_info.total_shares
+            .checked_add(shares)
+            .unwrap();
 
         emit!(DepositEvent {
             user: ctx.accounts.user.key(),
             mint: ctx.accounts.mint.key(),
             amount,
+            shares,
             chain_selector: 0, // Current chain
         });
 
         Ok(())
     }
 
-    /// Cross-chain borrow
-    pub fn borrow_cross_chain(
-        ctx: Context<BorrowCrossChain>,
-        amount: u64,
-        dest_chain: u64,
-        receiver: [u8; 32],
-    ) -> Result<()> {
-        require!(amount > 0, ErrorCode::InvalidAmount);
+    /// Lock receipt shares into the obligation's collateral escrow
+    pub fn lock_collateral(ctx: Context<LockCollateral>, shares: u64) -> Result<()> {
+        require!(shares > 0, ErrorCode::InvalidAmount);
         require!(!ctx.accounts.pool.is_paused, ErrorCode::NotAuthorized);
         require!(ctx.accounts.asset_info.is_active, ErrorCode::AssetNotSupported);
-        require!(ctx.accounts.asset_info.can_be_borrowed, ErrorCode::AssetNotSupported);
+        require!(ctx.accounts.asset_info.can_be_collateral, ErrorCode::AssetNotSupported);
+
+        // Move the shares from the user into escrow
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.user_share_account.to_account_info(),
+            to: ctx.accounts.collateral_escrow.to_account_info(),
+            authority: ctx.accounts.user.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        token::transfer(cpi_ctx, shares)?;
+
+        // Update obligation; adding collateral only makes it healthier, so it is not rate limited
+        let obligation = &mut ctx.accounts.obligation;
+        if obligation.owner == Pubkey::default() {
+            obligation.owner = ctx.accounts.user.key();
+            obligation.bump = ctx.bumps.obligation;
+        }
+
+        let mint = ctx.accounts.mint.key();
+        match obligation.find_deposit(mint) {
+            Some(index) => {
+                obligation.deposits[index].shares = obligation.deposits[index].shares
+                    .checked_add(shares)
+                    .unwrap();
+            }
+            None => {
+                // Isolated collateral cannot be combined with any other collateral
+                if obligation.isolated_mint != Some(mint) {
+                    require!(obligation.isolated_mint.is_none(), ErrorCode::IsolatedCollateralConflict);
+                    if ctx.accounts.asset_info.isolated {
+                        require!(
+                            obligation.deposits.is_empty() && obligation.borrows.is_empty(),
+                            ErrorCode::IsolatedCollateralConflict
+                        );
+                        obligation.isolated_mint = Some(mint);
+                    }
+                }
+                require!(
+                    obligation.deposits.len() < MAX_OBLIGATION_DEPOSITS,
+                    ErrorCode::ObligationReserveLimit
+                );
+                obligation.deposits.push(ObligationCollateral {
+                    mint,
+                    shares,
+                    market_value_usd: 0,
+                });
+            }
+        }
+        obligation.mark_stale();
+
+        emit!(CollateralLockedEvent {
+            user: ctx.accounts.user.key(),
+            mint,
+            shares,
+        });
+
+        Ok(())
+    }
+
+    /// Unlock receipt shares from the obligation's collateral escrow back to the user
+    pub fn unlock_collateral(ctx: Context<UnlockCollateral>, shares: u64) -> Result<()> {
+        require!(shares > 0, ErrorCode::InvalidAmount);
+        require!(!ctx.accounts.pool.is_paused, ErrorCode::NotAuthorized);
 
-        let user_position = &mut ctx.accounts.user_position;
-        require!(user_position.user != Pubkey::default(), ErrorCode::PositionNotFound);
+        let obligation = &mut ctx.accounts.obligation;
+        require!(obligation.owner != Pubkey::default(), ErrorCode::PositionNotFound);
 
         // Rate limiting check
         let current_time = Clock::get()?.unix_timestamp;
-        if user_position.last_action_timestamp + 900 > current_time {
+        if obligation.last_action_timestamp + 900 > current_time {
             return Err(ErrorCode::RateLimited.into());
         }
 
-        // Get asset price from Chainlink (simplified for now)
-        let price = get_asset_price(&ctx.accounts.price_feed)?;
-        let borrow_value_usd = calculate_usd_value(amount, price, ctx.accounts.mint.decimals)?;
+        let entry = obligation.find_deposit(ctx.accounts.mint.key()).ok_or(ErrorCode::PositionNotFound)?;
+        require!(obligation.deposits[entry].shares >= shares, ErrorCode::InvalidAmount);
 
-        // Calculate new total borrow value
-        let new_total_borrow_value = user_position.total_borrow_value_usd
-            .checked_add(borrow_value_usd)
-            .unwrap();
+        // Risk checks use the values from a refresh in this slot
+        let has_debt = !obligation.borrows.is_empty();
+        if has_debt {
+            obligation.require_fresh(Clock::get()?.slot)?;
+        }
 
-        // Check LTV ratio - use Bonk-specific LTV if applicable
-        let effective_ltv = if is_bonk_token(&ctx.accounts.mint.key()) {
-            get_bonk_config().0
-        } else {
-            ctx.accounts.asset_info.ltv
+        // Update obligation, removing the unlocked share of the refreshed collateral value
+        let emode = active_emode(
+            obligation.emode_category,
+            &[&ctx.accounts.asset_info],
+            ctx.accounts.emode_category.as_deref(),
+        )?;
+        obligation.remove_collateral(
+            entry,
+            shares,
+            emode.map_or(ctx.accounts.asset_info.ltv, |category| category.ltv),
+            emode.map_or(ctx.accounts.asset_info.liquidation_threshold, |category| category.liquidation_threshold),
+        )?;
+        obligation.prune();
+        obligation.last_action_timestamp = current_time;
+
+        // Unlocking must leave the obligation healthy
+        if has_debt {
+            obligation.update_health_factor()?;
+            require!(obligation.health_factor >= MIN_HEALTH_FACTOR, ErrorCode::HealthFactorTooLow);
+        }
+
+        // Return the shares from escrow to the user
+        let pool_bump = ctx.accounts.pool.bump;
+        let signer_seeds: &[&[&[u8]]] = &[&[b"pool", &[pool_bump]]];
+
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.collateral_escrow.to_account_info(),
+            to: ctx.accounts.user_share_account.to_account_info(),
+            authority: ctx.accounts.pool.to_account_info(),
         };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer_seeds);
+        token::transfer(cpi_ctx, shares)?;
 
-        let max_borrow_value = user_position.total_collateral_value_usd
-            .checked_mul(effective_ltv)
-            .unwrap()
-            .checked_div(PRECISION)
-            .unwrap();
+        emit!(CollateralUnlockedEvent {
+            user: ctx.accounts.user.key(),
+            mint: ctx.accounts.mint.key(),
+            shares,
+        });
 
-        require!(new_total_borrow_value <= max_borrow_value, ErrorCode::InsufficientCollateral);
+        Ok(())
+    }
 
-        // Update user position
-        user_position.borrow_balance = user_position.borrow_balance
-            .checked_add(amount)
-            .unwrap();
-        user_position.total_borrow_value_usd = new_total_borrow_value;
-        user_position.last_action_timestamp = current_time;
+    /// Cross-chain borrow
+    pub fn borrow_cross_chain(
+        ctx: Context<BorrowCrossChain>,
+        amount: u64,
+        dest_chain: u64,
+        receiver: [u8; 32],
+    ) -> Result<()> {
+        require!(amount > 0, ErrorCode::InvalidAmount);
+        require!(!ctx.accounts.pool.is_paused, ErrorCode::NotAuthorized);
+        require!(ctx.accounts.asset_info.is_active, ErrorCode::AssetNotSupported);
+        require!(ctx.accounts.asset_info.can_be_borrowed, ErrorCode::AssetNotSupported);
+        require!(amount >= ctx.accounts.asset_info.min_borrow_amount, ErrorCode::InvalidAmount);
 
-        // Calculate new health factor - use Bonk-specific liquidation threshold if applicable
-        let effective_liquidation_threshold = if is_bonk_token(&ctx.accounts.mint.key()) {
-            get_bonk_config().1
-        } else {
-            ctx.accounts.asset_info.liquidation_threshold
-        };
+        let obligation = &mut ctx.accounts.obligation;
+        require!(obligation.owner != Pubkey::default(), ErrorCode::PositionNotFound);
+        require!(
+            obligation.emode_category == 0
+                || ctx.accounts.asset_info.emode_category == obligation.emode_category,
+            ErrorCode::EModeCategoryMismatch
+        );
 
-        let new_health_factor = calculate_health_factor(
-            user_position.total_collateral_value_usd,
-            user_position.total_borrow_value_usd,
-            effective_liquidation_threshold,
+        // Rate limiting check
+        let current_time = Clock::get()?.unix_timestamp;
+        if obligation.last_action_timestamp + 900 > current_time {
+            return Err(ErrorCode::RateLimited.into());
+        }
+        ctx.accounts.asset_info.accrue_interest(current_time)?;
+
+        // Get asset price from the oracle
+        let asset_info = &ctx.accounts.asset_info;
+        let price = get_asset_price(
+            asset_info,
+            &ctx.accounts.price_feed,
+            ctx.accounts.secondary_price_feed.as_ref(),
         )?;
-
-        require!(new_health_factor >= MIN_HEALTH_FACTOR, ErrorCode::HealthFactorTooLow);
-        user_position.health_factor = new_health_factor;
+        let twap = twap_price(asset_info, ctx.accounts.price_history.as_deref(), current_time)?;
+        let borrow_value_usd = calculate_usd_value(
+            amount,
+            debt_price(price, twap),
+            ctx.accounts.mint.decimals,
+            Rounding::Up,
+        )
+        .map_err(ErrorCode::from)?;
+
+        record_borrow(
+            obligation,
+            &mut ctx.accounts.asset_info,
+            ctx.accounts.isolated_asset_info.as_deref_mut(),
+            amount,
+            borrow_value_usd,
+            Clock::get()?.slot,
+        )?;
+        obligation.last_action_timestamp = current_time;
 
         // Send cross-chain message
         send_ccip_message(
@@ -236,40 +624,116 @@ pub mod lending_pool {
             amount,
             dest_chain,
             receiver,
-            &ctx.remaining_accounts,
+            ctx.remaining_accounts,
         )?;
 
-        // Update asset info
-        let asset_info = &mut ctx.accounts.asset_info;
-        asset_info.total_borrows = asset_info.total_borrows
-            .checked_add(amount)
-            .unwrap();
-
         emit!(BorrowEvent {
             user: ctx.accounts.user.key(),
             mint: ctx.accounts.mint.key(),
             amount,
             dest_chain,
-            health_factor: new_health_factor,
+            health_factor: ctx.accounts.obligation.health_factor,
         });
 
         Ok(())
     }
 
-    /// Repay borrowed amount
-    pub fn repay(ctx: Context<RepayAccounts>, repay_amount: u64) -> Result<()> {
-        require!(repay_amount > 0, ErrorCode::InvalidAmount);
+    /// Borrow from the pool vault on this chain
+    pub fn borrow(ctx: Context<BorrowAccounts>, amount: u64) -> Result<()> {
+        require!(amount > 0, ErrorCode::InvalidAmount);
         require!(!ctx.accounts.pool.is_paused, ErrorCode::NotAuthorized);
+        require!(ctx.accounts.asset_info.is_active, ErrorCode::AssetNotSupported);
+        require!(ctx.accounts.asset_info.can_be_borrowed, ErrorCode::AssetNotSupported);
+        require!(amount >= ctx.accounts.asset_info.min_borrow_amount, ErrorCode::InvalidAmount);
 
-        let user_position = &mut ctx.accounts.user_position;
-        require!(user_position.user != Pubkey::default(), ErrorCode::PositionNotFound);
-        require!(user_position.borrow_balance >= repay_amount, ErrorCode::InvalidAmount);
+        let obligation = &mut ctx.accounts.obligation;
+        require!(obligation.owner != Pubkey::default(), ErrorCode::PositionNotFound);
+        require!(
+            obligation.emode_category == 0
+                || ctx.accounts.asset_info.emode_category == obligation.emode_category,
+            ErrorCode::EModeCategoryMismatch
+        );
 
         // Rate limiting check
         let current_time = Clock::get()?.unix_timestamp;
-        if user_position.last_action_timestamp + 900 > current_time {
+        if obligation.last_action_timestamp + 900 > current_time {
             return Err(ErrorCode::RateLimited.into());
         }
+        ctx.accounts.asset_info.accrue_interest(current_time)?;
+
+        // Only liquidity that is not already lent out can be borrowed
+        let asset_info = &ctx.accounts.asset_info;
+        require!(
+            amount <= asset_info.total_deposits.saturating_sub(asset_info.total_borrows),
+            ErrorCode::InsufficientLiquidity
+        );
+
+        // Get asset price from the oracle
+        let price = get_asset_price(
+            asset_info,
+            &ctx.accounts.price_feed,
+            ctx.accounts.secondary_price_feed.as_ref(),
+        )?;
+        let twap = twap_price(asset_info, ctx.accounts.price_history.as_deref(), current_time)?;
+        let borrow_value_usd = calculate_usd_value(
+            amount,
+            debt_price(price, twap),
+            ctx.accounts.mint.decimals,
+            Rounding::Up,
+        )
+        .map_err(ErrorCode::from)?;
+
+        record_borrow(
+            obligation,
+            &mut ctx.accounts.asset_info,
+            ctx.accounts.isolated_asset_info.as_deref_mut(),
+            amount,
+            borrow_value_usd,
+            Clock::get()?.slot,
+        )?;
+        obligation.last_action_timestamp = current_time;
+
+        // Transfer tokens from pool to user
+        let pool_bump = ctx.accounts.pool.bump;
+        let signer_seeds: &[&[&[u8]]] = &[&[b"pool", &[pool_bump]]];
+
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.pool_token_account.to_account_info(),
+            to: ctx.accounts.user_token_account.to_account_info(),
+            authority: ctx.accounts.pool.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer_seeds);
+        token::transfer(cpi_ctx, amount)?;
+
+        emit!(BorrowEvent {
+            user: ctx.accounts.user.key(),
+            mint: ctx.accounts.mint.key(),
+            amount,
+            dest_chain: 0, // Current chain
+            health_factor: ctx.accounts.obligation.health_factor,
+        });
+
+        Ok(())
+    }
+
+    /// Repay borrowed amount
+    pub fn repay(ctx: Context<RepayAccounts>, repay_amount: u64) -> Result<()> {
+        require!(repay_amount > 0, ErrorCode::InvalidAmount);
+        require!(!ctx.accounts.pool.is_paused, ErrorCode::NotAuthorized);
+
+        let obligation = &mut ctx.accounts.obligation;
+        require!(obligation.owner != Pubkey::default(), ErrorCode::PositionNotFound);
+
+        // Repaying only reduces risk, so it is exempt from the obligation-wide rate limit
+        let current_time = Clock::get()?.unix_timestamp;
+
+        // Bring the debt up to date before comparing against it
+        ctx.accounts.asset_info.accrue_interest(current_time)?;
+        let entry = obligation.find_borrow(ctx.accounts.mint.key()).ok_or(ErrorCode::PositionNotFound)?;
+        let borrow_index = ctx.accounts.asset_info.cumulative_borrow_index;
+        let current_debt = scaled_to_debt(obligation.borrows[entry].scaled_borrow_balance, borrow_index)?;
+        require!(current_debt >= repay_amount, ErrorCode::InvalidAmount);
 
         // Transfer tokens from user to pool
         let cpi_accounts = Transfer {
@@ -281,20 +745,30 @@ pub mod lending_pool {
         let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
         token::transfer(cpi_ctx, repay_amount)?;
 
-        // Update user position
-        user_position.borrow_balance = user_position.borrow_balance
-            .checked_sub(repay_amount)
-            .unwrap();
-        user_position.last_action_timestamp = current_time;
+        // Release the repaid share of any isolated debt
+        if let Some(isolated_reserve) = isolated_reserve(
+            obligation.isolated_mint,
+            &mut ctx.accounts.asset_info,
+            ctx.accounts.isolated_asset_info.as_deref_mut(),
+        )? {
+            release_isolated_debt(&mut obligation.borrows[entry], isolated_reserve, repay_amount, current_debt)?;
+        }
+
+        // Update obligation, rounding the scaled reduction down in the protocol's favour
+        let borrow = &mut obligation.borrows[entry];
+        let scaled_repaid = if repay_amount == current_debt {
+            borrow.scaled_borrow_balance
+        } else {
+            debt_to_scaled(repay_amount, borrow_index, Rounding::Down)?
+        };
+        borrow.scaled_borrow_balance = borrow.scaled_borrow_balance.checked_sub(scaled_repaid).unwrap();
+        obligation.prune();
+        obligation.mark_stale();
 
         // Update asset info
         let asset_info = &mut ctx.accounts.asset_info;
-        asset_info.total_borrows = asset_info.total_borrows
-            .checked_sub(repay_amount)
-            .unwrap();
-
-        // Update health factor
-        update_health_factor(user_position, &ctx.remaining_accounts)?;
+        asset_info.total_borrows = asset_info.total_borrows.saturating_sub(repay_amount);
+        asset_info.total_scaled_borrows = asset_info.total_scaled_borrows.checked_sub(scaled_repaid).unwrap();
 
         emit!(RepayEvent {
             user: ctx.accounts.user.key(),
@@ -305,44 +779,36 @@ pub mod lending_pool {
         Ok(())
     }
 
-    /// Withdraw collateral
+    /// Redeem receipt shares held by the user for the underlying liquidity
     pub fn withdraw(ctx: Context<WithdrawAccounts>, amount: u64) -> Result<()> {
         require!(amount > 0, ErrorCode::InvalidAmount);
         require!(!ctx.accounts.pool.is_paused, ErrorCode::NotAuthorized);
 
-        let user_position = &mut ctx.accounts.user_position;
-        require!(user_position.user != Pubkey::default(), ErrorCode::PositionNotFound);
-        require!(user_position.collateral_balance >= amount, ErrorCode::InvalidAmount);
-
-        // Rate limiting check
         let current_time = Clock::get()?.unix_timestamp;
-        if user_position.last_action_timestamp + 900 > current_time {
-            return Err(ErrorCode::RateLimited.into());
-        }
+        ctx.accounts.asset_info.accrue_interest(current_time)?;
 
-        // Check if withdrawal would make position unhealthy
-        let remaining_collateral = user_position.collateral_balance
+        // Shares to burn, rounded up in the protocol's favour
+        let shares = ctx.accounts.asset_info.amount_to_shares(amount, Rounding::Up)?;
+        require!(ctx.accounts.user_share_account.amount >= shares, ErrorCode::InvalidAmount);
+
+        // Update asset info
+        let asset_info = &mut ctx.accounts.asset_info;
+        asset_info.total_deposits = asset_info.total_deposits
             .checked_sub(amount)
             .unwrap();
+        asset_info.total_shares = asset_info.total_shares
+            .checked_sub(shares)
+            .unwrap();
 
-        if user_position.borrow_balance > 0 && remaining_collateral > 0 {
-            // Calculate health factor after withdrawal
-            let price = get_asset_price(&ctx.remaining_accounts[0])?;
-            let remaining_collateral_value = calculate_usd_value(
-                remaining_collateral,
-                price,
-                ctx.accounts.mint.decimals,
-            )?;
-
-            let new_health_factor = calculate_health_factor(
-                remaining_collateral_value,
-                user_position.total_borrow_value_usd,
-                ctx.accounts.asset_info.liquidation_threshold,
-            )?;
-
-            require!(new_health_factor >= MIN_HEALTH_FACTOR, ErrorCode::HealthFactorTooLow);
-            user_position.health_factor = new_health_factor;
-        }
+        // Burn the redeemed receipt shares
+        let cpi_accounts = Burn {
+            mint: ctx.accounts.share_mint.to_account_info(),
+            from: ctx.accounts.user_share_account.to_account_info(),
+            authority: ctx.accounts.user.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        token::burn(cpi_ctx, shares)?;
 
         // Transfer tokens from pool to user
         let pool_bump = ctx.accounts.pool.bump;
@@ -357,20 +823,11 @@ pub mod lending_pool {
         let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer_seeds);
         token::transfer(cpi_ctx, amount)?;
 
-        // Update user position
-        user_position.collateral_balance = remaining_collateral;
-        user_position.last_action_timestamp = current_time;
-
-        // Update asset info
-        let asset_info = &mut ctx.accounts.asset_info;
-        asset_info.total_deposits = asset_info.total_deposits
-            .checked_sub(amount)
-            .unwrap();
-
         emit!(WithdrawEvent {
             user: ctx.accounts.user.key(),
             mint: ctx.accounts.mint.key(),
             amount,
+            shares,
         });
 
         Ok(())
@@ -384,26 +841,127 @@ pub mod lending_pool {
         require!(debt_amount > 0, ErrorCode::InvalidAmount);
         require!(!ctx.accounts.pool.is_paused, ErrorCode::NotAuthorized);
 
-        let borrower_position = &mut ctx.accounts.borrower_position;
-        require!(borrower_position.user != Pubkey::default(), ErrorCode::PositionNotFound);
-
-        // Check if position is liquidatable
-        require!(borrower_position.health_factor < MIN_HEALTH_FACTOR, ErrorCode::LiquidationNotAllowed);
-        require!(borrower_position.borrow_balance >= debt_amount, ErrorCode::InvalidAmount);
+        let current_time = Clock::get()?.unix_timestamp;
+        ctx.accounts.debt_asset_info.accrue_interest(current_time)?;
+        ctx.accounts.collateral_asset_info.accrue_interest(current_time)?;
+
+        let obligation = &mut ctx.accounts.obligation;
+        require!(obligation.owner != Pubkey::default(), ErrorCode::PositionNotFound);
+
+        // Only an obligation refreshed in this slot can be judged liquidatable
+        let current_slot = Clock::get()?.slot;
+        obligation.require_fresh(current_slot)?;
+        require!(obligation.health_factor < MIN_HEALTH_FACTOR, ErrorCode::LiquidationNotAllowed);
+
+        let debt_mint = ctx.accounts.debt_mint.key();
+        let collateral_mint = ctx.accounts.collateral_mint.key();
+        let debt_entry = obligation.find_borrow(debt_mint).ok_or(ErrorCode::PositionNotFound)?;
+        let collateral_entry = obligation.find_deposit(collateral_mint).ok_or(ErrorCode::PositionNotFound)?;
+
+        let borrow_index = ctx.accounts.debt_asset_info.cumulative_borrow_index;
+        let current_debt = scaled_to_debt(obligation.borrows[debt_entry].scaled_borrow_balance, borrow_index)?;
+        require!(current_debt >= debt_amount, ErrorCode::InvalidAmount);
+
+        // Cap the repayment at the close factor unless the obligation is deeply unhealthy
+        let max_repay = max_liquidation_amount(
+            current_debt,
+            Decimal::from_scaled_val(obligation.borrows[debt_entry].market_value_usd),
+            ctx.accounts.debt_asset_info.close_factor,
+            obligation.health_factor,
+        )
+        .map_err(ErrorCode::from)?;
+        require!(debt_amount <= max_repay, ErrorCode::LiquidationTooLarge);
 
         // Get prices
-        let debt_price = get_asset_price(&ctx.accounts.debt_price_feed)?;
-        let collateral_price = get_asset_price(&ctx.accounts.collateral_price_feed)?;
+        let debt_price = get_asset_price(
+            &ctx.accounts.debt_asset_info,
+            &ctx.accounts.debt_price_feed,
+            ctx.accounts.debt_secondary_price_feed.as_ref(),
+        )?;
+        let collateral_price = get_asset_price(
+            &ctx.accounts.collateral_asset_info,
+            &ctx.accounts.collateral_price_feed,
+            ctx.accounts.collateral_secondary_price_feed.as_ref(),
+        )?;
+
+        // Use the e-mode bonus when collateral and debt share the obligation's category
+        let emode = active_emode(
+            obligation.emode_category,
+            &[&ctx.accounts.debt_asset_info, &ctx.accounts.collateral_asset_info],
+            ctx.accounts.emode_category.as_deref(),
+        )?;
+        let max_liquidation_bonus = emode.map_or(
+            ctx.accounts.collateral_asset_info.liquidation_bonus,
+            |category| category.liquidation_bonus,
+        );
+
+        // In Dutch-auction mode the bonus grows from the slot the obligation became liquidatable
+        let auction_slots = ctx.accounts.collateral_asset_info.liquidation_auction_slots;
+        let liquidation_bonus = if auction_slots > 0 {
+            dutch_auction_bonus(
+                max_liquidation_bonus,
+                obligation.liquidatable_since_slot,
+                current_slot,
+                auction_slots,
+            )
+        } else {
+            max_liquidation_bonus
+        };
 
-        // Calculate collateral to seize
-        let collateral_to_seize = calculate_liquidation_amount(
+        // Calculate collateral to seize, with and without the bonus
+        let debt_decimals = ctx.accounts.debt_mint.decimals;
+        let collateral_decimals = ctx.accounts.collateral_mint.decimals;
+        let mut collateral_to_seize = calculate_liquidation_amount(
             debt_amount,
+            debt_decimals,
             debt_price,
+            collateral_decimals,
             collateral_price,
-            LIQUIDATION_BONUS,
-        )?;
-
-        require!(borrower_position.collateral_balance >= collateral_to_seize, ErrorCode::InsufficientCollateral);
+            liquidation_bonus,
+        )
+        .map_err(ErrorCode::from)?;
+        let mut base_collateral = calculate_liquidation_amount(
+            debt_amount,
+            debt_decimals,
+            debt_price,
+            collateral_decimals,
+            collateral_price,
+            0,
+        )
+        .map_err(ErrorCode::from)?;
+
+        // When the deposit cannot cover the seizure, take all of it and scale the repayment down
+        // to match, so no dust collateral is left to block a bad-debt write-off
+        let mut debt_amount = debt_amount;
+        let deposit_shares = obligation.deposits[collateral_entry].shares;
+        let deposit_amount = ctx.accounts.collateral_asset_info.shares_to_amount(deposit_shares)?;
+        let shares_to_seize = if collateral_to_seize >= deposit_amount {
+            debt_amount = u64::try_from(
+                (debt_amount as u128 * deposit_amount as u128).div_ceil(collateral_to_seize as u128),
+            )
+            .map_err(|_| ErrorCode::InvalidAmount)?;
+            base_collateral = u64::try_from(
+                base_collateral as u128 * deposit_amount as u128 / collateral_to_seize as u128,
+            )
+            .map_err(|_| ErrorCode::InvalidAmount)?;
+            collateral_to_seize = deposit_amount;
+            deposit_shares
+        } else {
+            ctx.accounts.collateral_asset_info
+                .amount_to_shares(collateral_to_seize, Rounding::Up)?
+                .min(deposit_shares)
+        };
+        require!(debt_amount > 0 && collateral_to_seize > 0, ErrorCode::InsufficientCollateral);
+
+        // The protocol keeps a slice of the bonus as reserves
+        let bonus_amount = collateral_to_seize.saturating_sub(base_collateral);
+        let protocol_fee = Decimal::from_integer(bonus_amount)
+            .try_mul(
+                Decimal::from_scaled_val(ctx.accounts.collateral_asset_info.protocol_liquidation_fee.into()),
+                Rounding::Up,
+            )
+            .and_then(|fee| fee.try_to_u64(Rounding::Up))
+            .map_err(ErrorCode::from)?;
 
         // Transfer debt tokens from liquidator to pool
         let cpi_accounts = Transfer {
@@ -424,58 +982,252 @@ pub mod lending_pool {
             to: ctx.accounts.liquidator_collateral_account.to_account_info(),
             authority: ctx.accounts.pool.to_account_info(),
         };
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program.clone(), cpi_accounts, signer_seeds);
+        token::transfer(cpi_ctx, collateral_to_seize - protocol_fee)?;
+
+        // Burn the seized receipt shares held in escrow for the borrower
+        let cpi_accounts = Burn {
+            mint: ctx.accounts.collateral_share_mint.to_account_info(),
+            from: ctx.accounts.collateral_escrow.to_account_info(),
+            authority: ctx.accounts.pool.to_account_info(),
+        };
         let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer_seeds);
-        token::transfer(cpi_ctx, collateral_to_seize)?;
+        token::burn(cpi_ctx, shares_to_seize)?;
+
+        // Update borrower obligation, releasing isolated debt from the isolated collateral
+        if obligation.isolated_mint == Some(collateral_mint) {
+            release_isolated_debt(
+                &mut obligation.borrows[debt_entry],
+                &mut ctx.accounts.collateral_asset_info,
+                debt_amount,
+                current_debt,
+            )?;
+        }
+        let borrow = &mut obligation.borrows[debt_entry];
+        let repaid_value_usd = pro_rata_value(
+            Decimal::from_scaled_val(borrow.market_value_usd),
+            debt_amount,
+            current_debt,
+            Rounding::Down,
+        )
+        .map_err(ErrorCode::from)?
+        .to_scaled_val();
+        let scaled_repaid = if debt_amount == current_debt {
+            borrow.scaled_borrow_balance
+        } else {
+            debt_to_scaled(debt_amount, borrow_index, Rounding::Down)?
+        };
+        borrow.scaled_borrow_balance = borrow.scaled_borrow_balance.checked_sub(scaled_repaid).unwrap();
+        borrow.market_value_usd = borrow.market_value_usd.saturating_sub(repaid_value_usd);
+        obligation.borrowed_value_usd = obligation.borrowed_value_usd.saturating_sub(repaid_value_usd);
+
+        let collateral_emode = active_emode(
+            obligation.emode_category,
+            &[&ctx.accounts.collateral_asset_info],
+            ctx.accounts.emode_category.as_deref(),
+        )?;
+        obligation.remove_collateral(
+            collateral_entry,
+            shares_to_seize,
+            collateral_emode.map_or(ctx.accounts.collateral_asset_info.ltv, |category| category.ltv),
+            collateral_emode.map_or(
+                ctx.accounts.collateral_asset_info.liquidation_threshold,
+                |category| category.liquidation_threshold,
+            ),
+        )?;
+        obligation.prune();
+        obligation.update_health_factor()?;
+        obligation.update_liquidatable_since(current_slot);
 
-        // Update borrower position
-        borrower_position.borrow_balance = borrower_position.borrow_balance
-            .checked_sub(debt_amount)
-            .unwrap();
-        borrower_position.collateral_balance = borrower_position.collateral_balance
+        // Seized shares leave the supply together with the underlying they represent
+        let collateral_asset_info = &mut ctx.accounts.collateral_asset_info;
+        collateral_asset_info.total_deposits = collateral_asset_info.total_deposits
             .checked_sub(collateral_to_seize)
             .unwrap();
+        collateral_asset_info.total_shares = collateral_asset_info.total_shares
+            .checked_sub(shares_to_seize)
+            .unwrap();
+        collateral_asset_info.protocol_reserves = collateral_asset_info.protocol_reserves
+            .checked_add(protocol_fee)
+            .unwrap();
 
-        // Recalculate health factor
-        if borrower_position.borrow_balance > 0 {
-            let collateral_value = calculate_usd_value(
-                borrower_position.collateral_balance,
-                collateral_price,
-                ctx.accounts.collateral_mint.decimals,
-            )?;
-            let borrow_value = calculate_usd_value(
-                borrower_position.borrow_balance,
-                debt_price,
-                ctx.accounts.debt_mint.decimals,
-            )?;
-
-            borrower_position.health_factor = calculate_health_factor(
-                collateral_value,
-                borrow_value,
-                LIQUIDATION_THRESHOLD,
-            )?;
+        // With a single reserve both accounts alias it, and the collateral copy is written last
+        let debt_asset_info = if debt_mint == collateral_mint {
+            collateral_asset_info
         } else {
-            borrower_position.health_factor = u64::MAX; // No debt, maximum health
-        }
+            &mut ctx.accounts.debt_asset_info
+        };
+        debt_asset_info.total_borrows = debt_asset_info.total_borrows.saturating_sub(debt_amount);
+        debt_asset_info.total_scaled_borrows = debt_asset_info.total_scaled_borrows
+            .checked_sub(scaled_repaid)
+            .unwrap();
 
         emit!(LiquidationEvent {
             liquidator: ctx.accounts.liquidator.key(),
             borrower: ctx.accounts.borrower.key(),
             debt_amount,
             collateral_seized: collateral_to_seize,
-            health_factor: borrower_position.health_factor,
+            liquidation_bonus,
+            protocol_fee,
+            health_factor: ctx.accounts.obligation.health_factor,
         });
 
         Ok(())
     }
 
-    /// Receive cross-chain message via CCIP
-    pub fn ccip_receive(ctx: Context<CCIPReceive>, data: Vec<u8>) -> Result<()> {
+    /// Write off a borrow left on an obligation with no collateral (permissionless).
+    /// The loss is taken from protocol reserves first, then from the reserve's suppliers.
+    pub fn socialize_bad_debt(ctx: Context<SocializeBadDebt>) -> Result<()> {
         require!(!ctx.accounts.pool.is_paused, ErrorCode::NotAuthorized);
 
-        // Parse cross-chain message (simplified)
-        if data.len() < 64 {
-            return Err(ErrorCode::CrossChainFailed.into());
-        }
+        let current_time = Clock::get()?.unix_timestamp;
+        ctx.accounts.asset_info.accrue_interest(current_time)?;
+
+        let obligation = &mut ctx.accounts.obligation;
+        require!(obligation.owner != Pubkey::default(), ErrorCode::PositionNotFound);
+        require!(obligation.deposits.is_empty(), ErrorCode::ObligationHasCollateral);
+
+        let mint = ctx.accounts.mint.key();
+        let entry = obligation.find_borrow(mint).ok_or(ErrorCode::PositionNotFound)?;
+        let bad_debt = scaled_to_debt(
+            obligation.borrows[entry].scaled_borrow_balance,
+            ctx.accounts.asset_info.cumulative_borrow_index,
+        )?;
+
+        // The written-off debt no longer counts against the isolated collateral's ceiling
+        if let Some(isolated_reserve) = isolated_reserve(
+            obligation.isolated_mint,
+            &mut ctx.accounts.asset_info,
+            ctx.accounts.isolated_asset_info.as_deref_mut(),
+        )? {
+            release_isolated_debt(&mut obligation.borrows[entry], isolated_reserve, bad_debt, bad_debt)?;
+        }
+
+        // Remove the borrow from the obligation
+        let written_off_value_usd = obligation.borrows[entry].market_value_usd;
+        let written_off_scaled = obligation.borrows[entry].scaled_borrow_balance;
+        obligation.borrows[entry].scaled_borrow_balance = 0;
+        obligation.borrowed_value_usd = obligation.borrowed_value_usd.saturating_sub(written_off_value_usd);
+        obligation.prune();
+        obligation.mark_stale();
+
+        let (covered_by_reserves, socialized) = ctx.accounts.asset_info.write_off_debt(bad_debt, written_off_scaled);
+
+        emit!(BadDebtEvent {
+            borrower: ctx.accounts.borrower.key(),
+            mint,
+            amount: bad_debt,
+            covered_by_reserves,
+            socialized,
+        });
+
+        Ok(())
+    }
+
+    /// Borrow liquidity that must be repaid by flash_repay later in the same transaction
+    pub fn flash_borrow(ctx: Context<FlashLoan>, amount: u64) -> Result<()> {
+        require!(amount > 0, ErrorCode::InvalidAmount);
+        require!(!ctx.accounts.pool.is_paused, ErrorCode::NotAuthorized);
+        require!(ctx.accounts.asset_info.is_active, ErrorCode::AssetNotSupported);
+        require!(ctx.accounts.asset_info.can_be_borrowed, ErrorCode::AssetNotSupported);
+
+        let current_time = Clock::get()?.unix_timestamp;
+        ctx.accounts.asset_info.accrue_interest(current_time)?;
+        require!(
+            amount <= ctx.accounts.asset_info.total_deposits.saturating_sub(ctx.accounts.asset_info.total_borrows),
+            ErrorCode::InsufficientLiquidity
+        );
+
+        // Find the matching repay for this borrow later in the transaction
+        let instructions = ctx.accounts.instructions.to_account_info();
+        let current_index = load_current_index_checked(&instructions)? as usize;
+        check_flash_borrow(
+            get_stack_height(),
+            current_index,
+            amount,
+            ctx.accounts.asset_info.key(),
+            |index| load_instruction_at_checked(index, &instructions).ok(),
+        )?;
+
+        // Transfer tokens from pool to user
+        let pool_bump = ctx.accounts.pool.bump;
+        let signer_seeds: &[&[&[u8]]] = &[&[b"pool", &[pool_bump]]];
+
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.pool_token_account.to_account_info(),
+            to: ctx.accounts.user_token_account.to_account_info(),
+            authority: ctx.accounts.pool.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer_seeds);
+        token::transfer(cpi_ctx, amount)?;
+
+        let asset_info = &mut ctx.accounts.asset_info;
+        asset_info.total_borrows = asset_info.total_borrows
+            .checked_add(amount)
+            .unwrap();
+
+        Ok(())
+    }
+
+    /// Repay a flash loan plus fee
+    pub fn flash_repay(
+        ctx: Context<FlashLoan>,
+        amount: u64,
+        borrow_instruction_index: u8,
+    ) -> Result<()> {
+        // The referenced instruction must be the flash_borrow this repays
+        let instructions = ctx.accounts.instructions.to_account_info();
+        let current_index = load_current_index_checked(&instructions)? as usize;
+        check_flash_repay(
+            get_stack_height(),
+            current_index,
+            amount,
+            borrow_instruction_index,
+            ctx.accounts.asset_info.key(),
+            |index| load_instruction_at_checked(index, &instructions).ok(),
+        )?;
+
+        // Fee is rounded up in the protocol's favour
+        let fee = u64::try_from(
+            (amount as u128 * ctx.accounts.asset_info.flash_loan_fee as u128).div_ceil(PRECISION as u128),
+        )
+        .map_err(|_| ErrorCode::InvalidAmount)?;
+
+        // Transfer tokens from user to pool
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.user_token_account.to_account_info(),
+            to: ctx.accounts.pool_token_account.to_account_info(),
+            authority: ctx.accounts.user.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        token::transfer(cpi_ctx, amount.checked_add(fee).unwrap())?;
+
+        let asset_info = &mut ctx.accounts.asset_info;
+        asset_info.total_borrows = asset_info.total_borrows
+            .checked_sub(amount)
+            .unwrap();
+        asset_info.credit_interest(fee)?;
+
+        emit!(FlashLoanEvent {
+            user: ctx.accounts.user.key(),
+            mint: ctx.accounts.mint.key(),
+            amount,
+            fee,
+        });
+
+        Ok(())
+    }
+
+    /// Receive cross-chain message via CCIP
+    pub fn ccip_receive(ctx: Context<CCIPReceive>, data: Vec<u8>) -> Result<()> {
+        require!(!ctx.accounts.pool.is_paused, ErrorCode::NotAuthorized);
+
+        // Parse cross-chain message (simplified)
+        if data.len() < 64 {
+            return Err(ErrorCode::CrossChainFailed.into());
+        }
 
         // Mint synthetic asset to user
         let amount = u64::from_le_bytes([
@@ -501,6 +1253,154 @@ pub mod lending_pool {
         Ok(())
     }
 
+    /// Reprice an obligation from validated oracles and stamp the refresh slot (permissionless)
+    pub fn refresh_obligation(ctx: Context<RefreshObligation>) -> Result<()> {
+        let clock = Clock::get()?;
+        let obligation = &mut ctx.accounts.obligation;
+
+        let mut remaining_accounts = ctx.remaining_accounts;
+        let quotes = load_reserve_quotes(&mut remaining_accounts, obligation.reserve_count(), clock.unix_timestamp)?;
+        value_obligation(obligation, &quotes, ctx.accounts.emode_category.as_deref())?;
+        obligation.stamp_refresh(clock.slot);
+
+        emit!(ObligationRefreshedEvent {
+            owner: obligation.owner,
+            deposited_value_usd: obligation.deposited_value_usd,
+            borrowed_value_usd: obligation.borrowed_value_usd,
+            health_factor: obligation.health_factor,
+            slot: clock.slot,
+        });
+
+        Ok(())
+    }
+
+    /// Sample the oracle into the asset's price history (permissionless crank).
+    /// Emits `PriceDeviationEvent` instead of sampling when the sources disagree.
+    pub fn crank_price(ctx: Context<CrankPrice>) -> Result<()> {
+        let price = match read_asset_price(
+            &ctx.accounts.asset_info,
+            &ctx.accounts.price_feed,
+            ctx.accounts.secondary_price_feed.as_ref(),
+        )? {
+            AssetPrice::Valid(price) => price,
+            // Report the deviation without failing so the event is committed,
+            // but keep the disputed price out of the history
+            AssetPrice::Deviated(event) => {
+                emit!(event);
+                return Ok(());
+            }
+        };
+        let current_time = Clock::get()?.unix_timestamp;
+
+        let price_history = &mut ctx.accounts.price_history;
+        if price_history.mint == Pubkey::default() {
+            price_history.mint = ctx.accounts.asset_info.mint;
+            price_history.bump = ctx.bumps.price_history;
+        }
+
+        price_history.record_sample(price, current_time)?;
+
+        Ok(())
+    }
+
+    /// Move accumulated protocol reserves to the treasury (admin only)
+    pub fn collect_protocol_fees(ctx: Context<CollectProtocolFees>) -> Result<()> {
+        let current_time = Clock::get()?.unix_timestamp;
+        ctx.accounts.asset_info.accrue_interest(current_time)?;
+
+        // Reserves that are lent out cannot be collected until repaid
+        let amount = ctx.accounts.asset_info.protocol_reserves
+            .min(ctx.accounts.pool_token_account.amount);
+        require!(amount > 0, ErrorCode::InvalidAmount);
+
+        let pool_bump = ctx.accounts.pool.bump;
+        let signer_seeds: &[&[&[u8]]] = &[&[b"pool", &[pool_bump]]];
+
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.pool_token_account.to_account_info(),
+            to: ctx.accounts.treasury_token_account.to_account_info(),
+            authority: ctx.accounts.pool.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer_seeds);
+        token::transfer(cpi_ctx, amount)?;
+
+        let asset_info = &mut ctx.accounts.asset_info;
+        asset_info.protocol_reserves = asset_info.protocol_reserves
+            .checked_sub(amount)
+            .unwrap();
+
+        emit!(ProtocolFeesCollectedEvent {
+            mint: ctx.accounts.mint.key(),
+            treasury: ctx.accounts.treasury_token_account.key(),
+            amount,
+        });
+
+        Ok(())
+    }
+
+    /// Create an e-mode category for correlated assets (admin only)
+    pub fn add_emode_category(
+        ctx: Context<AddEModeCategory>,
+        category_id: u8,
+        config: EModeConfig,
+    ) -> Result<()> {
+        require!(category_id != 0, ErrorCode::InvalidEModeCategory);
+        require!(
+            config.ltv < config.liquidation_threshold && config.liquidation_threshold < PRECISION,
+            ErrorCode::InvalidEModeCategory
+        );
+        // Same bounds as a reserve's own parameters
+        require!(
+            liquidation_bonus_is_covered(config.liquidation_threshold, config.liquidation_bonus)
+                .map_err(ErrorCode::from)?,
+            ErrorCode::InvalidLiquidationBonus
+        );
+
+        let emode_category = &mut ctx.accounts.emode_category;
+        emode_category.id = category_id;
+        emode_category.label = config.label;
+        emode_category.ltv = config.ltv;
+        emode_category.liquidation_threshold = config.liquidation_threshold;
+        emode_category.liquidation_bonus = config.liquidation_bonus;
+        emode_category.bump = ctx.bumps.emode_category;
+
+        emit!(EModeCategoryAddedEvent {
+            category_id,
+            ltv: config.ltv,
+            liquidation_threshold: config.liquidation_threshold,
+            liquidation_bonus: config.liquidation_bonus,
+        });
+
+        Ok(())
+    }
+
+    /// Opt an obligation into an e-mode category, or out of e-mode with category 0
+    pub fn set_user_emode(ctx: Context<SetUserEMode>, category_id: u8) -> Result<()> {
+        let obligation = &mut ctx.accounts.obligation;
+        require!(obligation.owner != Pubkey::default(), ErrorCode::PositionNotFound);
+        require!(
+            category_id == 0 || ctx.accounts.emode_category.is_some(),
+            ErrorCode::InvalidEModeCategory
+        );
+        obligation.emode_category = category_id;
+
+        // Every borrow must be in the category and the obligation must stay healthy
+        let clock = Clock::get()?;
+        let mut remaining_accounts = ctx.remaining_accounts;
+        let quotes = load_reserve_quotes(&mut remaining_accounts, obligation.reserve_count(), clock.unix_timestamp)?;
+        value_obligation(obligation, &quotes, ctx.accounts.emode_category.as_deref())?;
+        require!(obligation.health_factor >= MIN_HEALTH_FACTOR, ErrorCode::HealthFactorTooLow);
+        obligation.stamp_refresh(clock.slot);
+
+        emit!(UserEModeSetEvent {
+            user: ctx.accounts.user.key(),
+            category_id,
+        });
+
+        Ok(())
+    }
+
     /// Pause the protocol (admin only)
     pub fn pause(ctx: Context<AdminAction>) -> Result<()> {
         let pool = &mut ctx.accounts.pool;
@@ -531,6 +1431,7 @@ pub mod lending_pool {
 pub struct Pool {
     pub admin: Pubkey,
     pub ccip_program: Pubkey,
+    pub treasury: Pubkey,
     pub is_paused: bool,
     pub total_assets: u32,
     pub bump: u8,
@@ -539,37 +1440,654 @@ pub struct Pool {
 #[account]
 pub struct AssetInfo {
     pub mint: Pubkey,
+    pub decimals: u8,
     pub price_feed: Pubkey,
+    pub oracle_source: OracleSource,
+    pub oracle_program: Pubkey,
+    pub secondary_price_feed: Option<Pubkey>,
+    pub secondary_oracle_source: OracleSource,
+    pub secondary_oracle_program: Pubkey,
+    pub price_aggregation: PriceAggregation,
+    pub max_price_deviation_bps: u64,
+    pub use_twap: bool,
     pub ltv: u64,
     pub liquidation_threshold: u64,
+    pub liquidation_bonus: u64,
+    pub protocol_liquidation_fee: u64,
+    pub liquidation_auction_slots: u64,
     pub is_active: bool,
     pub can_be_collateral: bool,
     pub can_be_borrowed: bool,
-    pub total_deposits: u64,
+    pub share_mint: Pubkey,
+    pub total_deposits: u64, // Supplied liquidity including accrued interest
     pub total_borrows: u64,
+    pub total_scaled_borrows: u64, // Exact sum of the obligations' scaled balances
+    pub total_shares: u64,
+    pub interest_rate_model: InterestRateModel,
+    pub reserve_factor: u64,
+    pub protocol_reserves: u64,
+    pub flash_loan_fee: u64,
+    pub supply_cap: u64,
+    pub borrow_cap: u64,
+    pub risk_tier: RiskTier,
+    pub min_deposit_amount: u64,
+    pub min_borrow_amount: u64,
+    pub isolated: bool,
+    pub debt_ceiling_usd: u128,
+    pub borrowable_in_isolation: bool,
+    pub isolated_debt_usd: u128,
+    pub emode_category: u8,
+    pub close_factor: u64,
+    pub delist_timestamp: i64, // Start of the wind-down, 0 while listed
+    pub delist_wind_down_seconds: i64,
+    pub delist_liquidation_threshold: u64, // Threshold the wind-down steps down from
+    pub cumulative_borrow_index: u128, // Rate scaled value
+    pub last_accrual_timestamp: i64,
     pub bump: u8,
 }
 
+/// A user's deposits and borrows across every reserve, valued together for health checks.
+/// refresh_obligation takes, in remaining accounts, each distinct reserve's AssetInfo
+/// and price feed, followed by its secondary feed and price history when the reserve uses them.
 #[account]
-pub struct UserPosition {
-    pub user: Pubkey,
-    pub collateral_balance: u64,
-    pub borrow_balance: u64,
-    pub total_collateral_value_usd: u64,
-    pub total_borrow_value_usd: u64,
-    pub health_factor: u64,
+pub struct Obligation {
+    pub owner: Pubkey,
+    pub deposits: Vec<ObligationCollateral>,
+    pub borrows: Vec<ObligationLiquidity>,
+    pub isolated_mint: Option<Pubkey>, // Isolated collateral backing every borrow, if any
+    pub emode_category: u8, // 0 when not in e-mode
+    pub deposited_value_usd: u128, // USD values are Decimal scaled values
+    pub borrowed_value_usd: u128,
+    pub allowed_borrow_value_usd: u128, // Collateral value weighted by LTV
+    pub unhealthy_borrow_value_usd: u128, // Collateral value weighted by liquidation threshold
+    pub health_factor: u64, // Scaled by PRECISION, u64::MAX without debt
+    pub last_update_slot: u64, // Slot of the last refresh, 0 once balances change unpriced
+    pub liquidatable_since_slot: u64, // First refresh slot found unhealthy, 0 while healthy
     pub last_action_timestamp: i64,
     pub bump: u8,
 }
 
-// Configuration structs
-#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-pub struct AssetConfig {
-    pub price_feed: Pubkey,
-    pub ltv: u64,
-    pub liquidation_threshold: u64,
-    pub can_be_collateral: bool,
-    pub can_be_borrowed: bool,
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ObligationCollateral {
+    pub mint: Pubkey,
+    pub shares: u64, // Receipt shares backing this deposit
+    pub market_value_usd: u128,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ObligationLiquidity {
+    pub mint: Pubkey,
+    pub scaled_borrow_balance: u64, // Principal divided by the borrow index at borrow time
+    pub isolated_debt_usd: u128, // Debt counted against the isolated collateral's ceiling
+    pub market_value_usd: u128,
+}
+
+impl Obligation {
+    pub const SPACE: usize = 8 + 32
+        + (4 + MAX_OBLIGATION_DEPOSITS * (32 + 8 + 16))
+        + (4 + MAX_OBLIGATION_BORROWS * (32 + 8 + 16 + 16))
+        + (1 + 32) + 1 + 16 + 16 + 16 + 16 + 8 + 8 + 8 + 8 + 1;
+
+    pub fn find_deposit(&self, mint: Pubkey) -> Option<usize> {
+        self.deposits.iter().position(|deposit| deposit.mint == mint)
+    }
+
+    pub fn find_borrow(&self, mint: Pubkey) -> Option<usize> {
+        self.borrows.iter().position(|borrow| borrow.mint == mint)
+    }
+
+    /// Number of distinct reserves that must be passed in to value the obligation
+    pub fn reserve_count(&self) -> usize {
+        self.deposits.len()
+            + self.borrows
+                .iter()
+                .filter(|borrow| self.find_deposit(borrow.mint).is_none())
+                .count()
+    }
+
+    pub fn require_fresh(&self, current_slot: u64) -> Result<()> {
+        require!(self.last_update_slot == current_slot, ErrorCode::ObligationStale);
+        Ok(())
+    }
+
+    /// Record a refresh, starting the liquidation clock the first time the obligation is unhealthy
+    pub fn stamp_refresh(&mut self, current_slot: u64) {
+        self.last_update_slot = current_slot;
+        self.update_liquidatable_since(current_slot);
+    }
+
+    pub fn update_liquidatable_since(&mut self, current_slot: u64) {
+        if self.health_factor >= MIN_HEALTH_FACTOR {
+            self.liquidatable_since_slot = 0;
+        } else if self.liquidatable_since_slot == 0 {
+            self.liquidatable_since_slot = current_slot;
+        }
+    }
+
+    /// Require a refresh before the next risk check
+    pub fn mark_stale(&mut self) {
+        self.last_update_slot = 0;
+    }
+
+    pub fn values(&self) -> ObligationValues {
+        ObligationValues {
+            deposited_value_usd: Decimal::from_scaled_val(self.deposited_value_usd),
+            borrowed_value_usd: Decimal::from_scaled_val(self.borrowed_value_usd),
+            allowed_borrow_value_usd: Decimal::from_scaled_val(self.allowed_borrow_value_usd),
+            unhealthy_borrow_value_usd: Decimal::from_scaled_val(self.unhealthy_borrow_value_usd),
+        }
+    }
+
+    pub fn set_values(&mut self, values: ObligationValues) {
+        self.deposited_value_usd = values.deposited_value_usd.to_scaled_val();
+        self.borrowed_value_usd = values.borrowed_value_usd.to_scaled_val();
+        self.allowed_borrow_value_usd = values.allowed_borrow_value_usd.to_scaled_val();
+        self.unhealthy_borrow_value_usd = values.unhealthy_borrow_value_usd.to_scaled_val();
+    }
+
+    pub fn update_health_factor(&mut self) -> Result<()> {
+        self.health_factor = self.values().health_factor().map_err(ErrorCode::from)?;
+        Ok(())
+    }
+
+    /// Take shares out of a deposit along with their share of its refreshed value,
+    /// rounding the value removed up in the protocol's favour
+    pub fn remove_collateral(
+        &mut self,
+        entry: usize,
+        shares: u64,
+        ltv: u64,
+        liquidation_threshold: u64,
+    ) -> Result<()> {
+        let deposit = &mut self.deposits[entry];
+        let market_value_usd = Decimal::from_scaled_val(deposit.market_value_usd);
+        let value_usd = pro_rata_value(market_value_usd, shares, deposit.shares, Rounding::Up)
+            .map_err(ErrorCode::from)?;
+        deposit.shares = deposit.shares
+            .checked_sub(shares)
+            .ok_or(ErrorCode::InvalidAmount)?;
+        deposit.market_value_usd = market_value_usd.saturating_sub(value_usd).to_scaled_val();
+
+        let mut values = self.values();
+        values
+            .remove_collateral(value_usd, ltv, liquidation_threshold)
+            .map_err(ErrorCode::from)?;
+        self.set_values(values);
+        Ok(())
+    }
+
+    /// Drop emptied entries and leave isolation mode once nothing is left
+    pub fn prune(&mut self) {
+        self.deposits.retain(|deposit| deposit.shares > 0);
+        self.borrows.retain(|borrow| borrow.scaled_borrow_balance > 0);
+        if self.deposits.is_empty() && self.borrows.is_empty() {
+            self.isolated_mint = None;
+        }
+    }
+}
+
+/// Risk parameters for correlated assets, e.g. USD stablecoins or SOL and its LSTs
+#[account]
+pub struct EModeCategory {
+    pub id: u8,
+    pub label: [u8; 32],
+    pub ltv: u64,
+    pub liquidation_threshold: u64,
+    pub liquidation_bonus: u64,
+    pub bump: u8,
+}
+
+/// Ring buffer of oracle samples with time-weighted and exponential averages
+#[account]
+pub struct PriceHistory {
+    pub mint: Pubkey,
+    pub samples: [PriceSample; PRICE_HISTORY_CAPACITY],
+    pub head: u8,
+    pub len: u8,
+    pub twap: u64,
+    pub ema: u64,
+    pub last_update_timestamp: i64,
+    pub bump: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct PriceSample {
+    pub price: u64,
+    pub timestamp: i64,
+}
+
+impl PriceHistory {
+    pub const SPACE: usize = 8 + 32 + PRICE_HISTORY_CAPACITY * (8 + 8) + 1 + 1 + 8 + 8 + 8 + 1;
+
+    /// Append a sample and refresh the TWAP and EMA, at most once per minimum sample interval
+    pub fn record_sample(&mut self, price: u64, timestamp: i64) -> Result<()> {
+        if self.len > 0 && self.last_update_timestamp + MIN_PRICE_SAMPLE_INTERVAL_SECONDS > timestamp {
+            return Err(ErrorCode::RateLimited.into());
+        }
+
+        // Update the EMA before overwriting the last timestamp
+        self.ema = if self.len == 0 {
+            price
+        } else {
+            let elapsed = (timestamp - self.last_update_timestamp).clamp(0, EMA_PERIOD_SECONDS) as u128;
+            let period = EMA_PERIOD_SECONDS as u128;
+            let ema = (self.ema as u128 * (period - elapsed) + price as u128 * elapsed) / period;
+            u64::try_from(ema).map_err(|_| ErrorCode::InvalidPriceData)?
+        };
+
+        self.samples[self.head as usize] = PriceSample { price, timestamp };
+        self.head = ((self.head as usize + 1) % PRICE_HISTORY_CAPACITY) as u8;
+        self.len = (self.len as usize + 1).min(PRICE_HISTORY_CAPACITY) as u8;
+        self.last_update_timestamp = timestamp;
+        self.twap = self.time_weighted_average()?;
+
+        Ok(())
+    }
+
+    /// Average of the buffered samples, each weighted by how long it was the latest price
+    fn time_weighted_average(&self) -> Result<u64> {
+        let len = self.len as usize;
+        let oldest = (self.head as usize + PRICE_HISTORY_CAPACITY - len) % PRICE_HISTORY_CAPACITY;
+
+        let mut weighted_sum: u128 = 0;
+        let mut total_time: u128 = 0;
+        for i in 0..len.saturating_sub(1) {
+            let sample = self.samples[(oldest + i) % PRICE_HISTORY_CAPACITY];
+            let next = self.samples[(oldest + i + 1) % PRICE_HISTORY_CAPACITY];
+            let elapsed = next.timestamp.saturating_sub(sample.timestamp).max(0) as u128;
+            weighted_sum = weighted_sum
+                .checked_add(sample.price as u128 * elapsed)
+                .ok_or(ErrorCode::InvalidPriceData)?;
+            total_time += elapsed;
+        }
+
+        if total_time == 0 {
+            // A single sample is its own average
+            let latest = (self.head as usize + PRICE_HISTORY_CAPACITY - 1) % PRICE_HISTORY_CAPACITY;
+            return Ok(self.samples[latest].price);
+        }
+
+        u64::try_from(weighted_sum / total_time).map_err(|_| ErrorCode::InvalidPriceData.into())
+    }
+}
+
+// Configuration structs
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct AssetConfig {
+    pub price_feed: Pubkey,
+    pub oracle_source: OracleSource,
+    pub oracle_program: Pubkey,
+    pub secondary_price_feed: Option<Pubkey>,
+    pub secondary_oracle_source: OracleSource,
+    pub secondary_oracle_program: Pubkey,
+    pub price_aggregation: PriceAggregation,
+    pub max_price_deviation_bps: u64,
+    pub use_twap: bool,
+    pub ltv: u64,
+    pub liquidation_threshold: u64,
+    pub liquidation_bonus: u64, // Extra collateral paid to liquidators, scaled by PRECISION
+    pub protocol_liquidation_fee: u64, // Share of the bonus kept as protocol reserves, scaled by PRECISION
+    pub liquidation_auction_slots: u64, // Slots for a Dutch-auction bonus to reach liquidation_bonus, 0 for a fixed bonus
+    pub can_be_collateral: bool,
+    pub can_be_borrowed: bool,
+    pub interest_rate_model: InterestRateModel,
+    pub reserve_factor: u64, // Share of interest kept as protocol reserves, scaled by PRECISION
+    pub flash_loan_fee: u64, // Fee charged on flash loans, scaled by PRECISION
+    pub supply_cap: u64, // Maximum total deposits in token units, 0 for no cap
+    pub borrow_cap: u64, // Maximum total borrows in token units, 0 for no cap
+    pub risk_tier: RiskTier,
+    pub min_deposit_amount: u64,
+    pub min_borrow_amount: u64,
+    pub isolated: bool, // Collateral that may only back borrows of isolation-borrowable assets
+    pub debt_ceiling_usd: u128, // Maximum total debt backed by this asset when isolated, scaled by PRECISION
+    pub borrowable_in_isolation: bool, // Allow-listed (e.g. stablecoins) for isolated collateral
+    pub emode_category: u8, // 0 when the asset belongs to no e-mode category
+    pub close_factor: u64, // Share of a borrow one liquidation may repay, scaled by PRECISION
+}
+
+impl AssetConfig {
+    pub fn params(&self) -> AssetParams {
+        AssetParams {
+            price_feed: self.price_feed,
+            oracle_source: self.oracle_source,
+            oracle_program: self.oracle_program,
+            ltv: self.ltv,
+            liquidation_threshold: self.liquidation_threshold,
+            liquidation_bonus: self.liquidation_bonus,
+            protocol_liquidation_fee: self.protocol_liquidation_fee,
+            can_be_collateral: self.can_be_collateral,
+            can_be_borrowed: self.can_be_borrowed,
+            is_active: true,
+            interest_rate_model: self.interest_rate_model,
+            reserve_factor: self.reserve_factor,
+            supply_cap: self.supply_cap,
+            borrow_cap: self.borrow_cap,
+            close_factor: self.close_factor,
+        }
+    }
+}
+
+/// Reserve parameters that can be changed after listing
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct AssetParams {
+    pub price_feed: Pubkey,
+    pub oracle_source: OracleSource,
+    pub oracle_program: Pubkey,
+    pub ltv: u64,
+    pub liquidation_threshold: u64,
+    pub liquidation_bonus: u64,
+    pub protocol_liquidation_fee: u64,
+    pub can_be_collateral: bool,
+    pub can_be_borrowed: bool,
+    pub is_active: bool,
+    pub interest_rate_model: InterestRateModel,
+    pub reserve_factor: u64,
+    pub supply_cap: u64,
+    pub borrow_cap: u64,
+    pub close_factor: u64,
+}
+
+impl AssetParams {
+    pub fn validate(&self, risk_tier: RiskTier) -> Result<()> {
+        self.interest_rate_model.validate()?;
+        require!(self.reserve_factor <= PRECISION, ErrorCode::InvalidReserveFactor);
+        require!(
+            self.close_factor > 0 && self.close_factor <= PRECISION,
+            ErrorCode::InvalidCloseFactor
+        );
+        // Borrowing power must stop short of the liquidation point. Assets that cannot back
+        // borrows may leave both at zero.
+        require!(
+            (self.ltv < self.liquidation_threshold && self.liquidation_threshold <= PRECISION)
+                || (!self.can_be_collateral && self.ltv == 0 && self.liquidation_threshold == 0),
+            ErrorCode::InvalidRiskParameters
+        );
+        // Seizing threshold-weighted collateral plus the bonus must not exceed the collateral itself
+        require!(
+            liquidation_bonus_is_covered(self.liquidation_threshold, self.liquidation_bonus)
+                .map_err(ErrorCode::from)?
+                && self.protocol_liquidation_fee <= PRECISION,
+            ErrorCode::InvalidLiquidationBonus
+        );
+        require!(
+            self.ltv <= risk_tier.max_ltv()
+                && self.liquidation_threshold <= risk_tier.max_liquidation_threshold(),
+            ErrorCode::RiskTierLimitExceeded
+        );
+        Ok(())
+    }
+}
+
+/// Changes for update_asset_config, None leaves a parameter as it is
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct AssetConfigUpdate {
+    pub price_feed: Option<Pubkey>,
+    pub oracle_source: Option<OracleSource>,
+    pub oracle_program: Option<Pubkey>,
+    pub ltv: Option<u64>,
+    pub liquidation_threshold: Option<u64>,
+    pub liquidation_bonus: Option<u64>,
+    pub protocol_liquidation_fee: Option<u64>,
+    pub can_be_collateral: Option<bool>,
+    pub can_be_borrowed: Option<bool>,
+    pub is_active: Option<bool>,
+    pub interest_rate_model: Option<InterestRateModel>,
+    pub reserve_factor: Option<u64>,
+    pub supply_cap: Option<u64>,
+    pub borrow_cap: Option<u64>,
+    pub close_factor: Option<u64>,
+}
+
+impl AssetConfigUpdate {
+    pub fn apply(&self, params: &AssetParams) -> AssetParams {
+        AssetParams {
+            price_feed: self.price_feed.unwrap_or(params.price_feed),
+            oracle_source: self.oracle_source.unwrap_or(params.oracle_source),
+            oracle_program: self.oracle_program.unwrap_or(params.oracle_program),
+            ltv: self.ltv.unwrap_or(params.ltv),
+            liquidation_threshold: self.liquidation_threshold.unwrap_or(params.liquidation_threshold),
+            liquidation_bonus: self.liquidation_bonus.unwrap_or(params.liquidation_bonus),
+            protocol_liquidation_fee: self.protocol_liquidation_fee.unwrap_or(params.protocol_liquidation_fee),
+            can_be_collateral: self.can_be_collateral.unwrap_or(params.can_be_collateral),
+            can_be_borrowed: self.can_be_borrowed.unwrap_or(params.can_be_borrowed),
+            is_active: self.is_active.unwrap_or(params.is_active),
+            interest_rate_model: self.interest_rate_model.unwrap_or(params.interest_rate_model),
+            reserve_factor: self.reserve_factor.unwrap_or(params.reserve_factor),
+            supply_cap: self.supply_cap.unwrap_or(params.supply_cap),
+            borrow_cap: self.borrow_cap.unwrap_or(params.borrow_cap),
+            close_factor: self.close_factor.unwrap_or(params.close_factor),
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct EModeConfig {
+    pub label: [u8; 32],
+    pub ltv: u64,
+    pub liquidation_threshold: u64,
+    pub liquidation_bonus: u64,
+}
+
+/// Risk classification bounding an asset's LTV and liquidation threshold
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RiskTier {
+    Stable,
+    Standard,
+    Volatile,
+}
+
+impl RiskTier {
+    pub fn max_ltv(&self) -> u64 {
+        match self {
+            RiskTier::Stable => STABLE_MAX_LTV,
+            RiskTier::Standard => STANDARD_MAX_LTV,
+            RiskTier::Volatile => VOLATILE_MAX_LTV,
+        }
+    }
+
+    pub fn max_liquidation_threshold(&self) -> u64 {
+        match self {
+            RiskTier::Stable => STABLE_MAX_LIQUIDATION_THRESHOLD,
+            RiskTier::Standard => STANDARD_MAX_LIQUIDATION_THRESHOLD,
+            RiskTier::Volatile => VOLATILE_MAX_LIQUIDATION_THRESHOLD,
+        }
+    }
+}
+
+/// Kinked utilization rate model, all rates annual and scaled by PRECISION
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct InterestRateModel {
+    pub base_rate: u64,
+    pub slope1: u64,
+    pub optimal_utilization: u64,
+    pub slope2: u64,
+}
+
+impl InterestRateModel {
+    pub fn validate(&self) -> Result<()> {
+        require!(
+            self.optimal_utilization > 0 && self.optimal_utilization <= PRECISION,
+            ErrorCode::InvalidInterestRateModel
+        );
+        Ok(())
+    }
+
+    /// Annual borrow rate at the given utilization
+    pub fn borrow_rate(&self, utilization: u64) -> Result<u64> {
+        let rate = if utilization <= self.optimal_utilization {
+            self.base_rate as u128
+                + self.slope1 as u128 * utilization as u128 / self.optimal_utilization as u128
+        } else {
+            let excess = (utilization - self.optimal_utilization) as u128;
+            let max_excess = (PRECISION - self.optimal_utilization) as u128;
+            self.base_rate as u128 + self.slope1 as u128 + self.slope2 as u128 * excess / max_excess
+        };
+        u64::try_from(rate).map_err(|_| ErrorCode::InvalidInterestRateModel.into())
+    }
+}
+
+impl AssetInfo {
+    pub fn params(&self) -> AssetParams {
+        AssetParams {
+            price_feed: self.price_feed,
+            oracle_source: self.oracle_source,
+            oracle_program: self.oracle_program,
+            ltv: self.ltv,
+            liquidation_threshold: self.liquidation_threshold,
+            liquidation_bonus: self.liquidation_bonus,
+            protocol_liquidation_fee: self.protocol_liquidation_fee,
+            can_be_collateral: self.can_be_collateral,
+            can_be_borrowed: self.can_be_borrowed,
+            is_active: self.is_active,
+            interest_rate_model: self.interest_rate_model,
+            reserve_factor: self.reserve_factor,
+            supply_cap: self.supply_cap,
+            borrow_cap: self.borrow_cap,
+            close_factor: self.close_factor,
+        }
+    }
+
+    pub fn set_params(&mut self, params: &AssetParams) {
+        self.price_feed = params.price_feed;
+        self.oracle_source = params.oracle_source;
+        self.oracle_program = params.oracle_program;
+        self.ltv = params.ltv;
+        self.liquidation_threshold = params.liquidation_threshold;
+        self.liquidation_bonus = params.liquidation_bonus;
+        self.protocol_liquidation_fee = params.protocol_liquidation_fee;
+        self.can_be_collateral = params.can_be_collateral;
+        self.can_be_borrowed = params.can_be_borrowed;
+        self.is_active = params.is_active;
+        self.interest_rate_model = params.interest_rate_model;
+        self.reserve_factor = params.reserve_factor;
+        self.supply_cap = params.supply_cap;
+        self.borrow_cap = params.borrow_cap;
+        self.close_factor = params.close_factor;
+    }
+
+    /// Borrowed share of deposits, scaled by PRECISION and capped at 1.0
+    pub fn utilization(&self) -> Result<u64> {
+        if self.total_deposits == 0 {
+            return Ok(0);
+        }
+        let utilization = Decimal::from_ratio(self.total_borrows, self.total_deposits, Rounding::Down)
+            .map_err(ErrorCode::from)?
+            .min(Decimal::ONE);
+        Ok(utilization.to_scaled_val() as u64)
+    }
+
+    /// Receipt shares worth `amount` of the underlying at the current exchange rate
+    pub fn amount_to_shares(&self, amount: u64, rounding: Rounding) -> Result<u64> {
+        if self.total_shares == 0 {
+            return Ok(amount);
+        }
+        // Shares left with nothing behind them after a full write-off must not be diluted 1:1
+        require!(self.total_deposits > 0, ErrorCode::ReserveInsolvent);
+        let shares = mul_div(amount.into(), self.total_shares.into(), self.total_deposits.into(), rounding)
+            .map_err(ErrorCode::from)?;
+        u64::try_from(shares).map_err(|_| ErrorCode::InvalidAmount.into())
+    }
+
+    /// Write off bad debt, covering it from reserves first and then lowering the suppliers'
+    /// exchange rate. Returns the amounts covered by reserves and socialized.
+    pub fn write_off_debt(&mut self, bad_debt: u64, scaled_debt: u64) -> (u64, u64) {
+        let covered_by_reserves = bad_debt.min(self.protocol_reserves);
+        let socialized = bad_debt - covered_by_reserves;
+        self.protocol_reserves -= covered_by_reserves;
+        self.total_deposits = self.total_deposits.saturating_sub(socialized);
+        self.total_borrows = self.total_borrows.saturating_sub(bad_debt);
+        self.total_scaled_borrows = self.total_scaled_borrows
+            .checked_sub(scaled_debt)
+            .unwrap();
+        (covered_by_reserves, socialized)
+    }
+
+    /// Underlying redeemable for `shares`, rounded down in the protocol's favour
+    pub fn shares_to_amount(&self, shares: u64) -> Result<u64> {
+        if self.total_shares == 0 {
+            return Ok(shares);
+        }
+        let amount = mul_div(shares.into(), self.total_deposits.into(), self.total_shares.into(), Rounding::Down)
+            .map_err(ErrorCode::from)?;
+        u64::try_from(amount).map_err(|_| ErrorCode::InvalidAmount.into())
+    }
+
+    pub fn is_delisted(&self) -> bool {
+        self.delist_timestamp != 0
+    }
+
+    /// Liquidation threshold of a delisted reserve, falling linearly to zero over the wind-down
+    pub fn wind_down_threshold(&self, current_time: i64) -> u64 {
+        let elapsed = current_time
+            .saturating_sub(self.delist_timestamp)
+            .clamp(0, self.delist_wind_down_seconds);
+        let remaining = (self.delist_wind_down_seconds - elapsed) as u128;
+        (self.delist_liquidation_threshold as u128 * remaining / self.delist_wind_down_seconds as u128) as u64
+    }
+
+    /// Grow the borrow index and totals by the interest accrued since the last update,
+    /// and step a delisted reserve's liquidation threshold down
+    pub fn accrue_interest(&mut self, current_time: i64) -> Result<()> {
+        if self.is_delisted() {
+            self.liquidation_threshold = self.wind_down_threshold(current_time);
+        }
+
+        let elapsed = current_time.saturating_sub(self.last_accrual_timestamp);
+        if elapsed <= 0 {
+            return Ok(());
+        }
+
+        // Simple interest over the elapsed period, compounded into the index at each accrual
+        let borrow_rate = Rate::from_wad(self.interest_rate_model.borrow_rate(self.utilization()?)?);
+        let interest_factor = borrow_rate
+            .try_mul_int(elapsed as u64)
+            .and_then(|rate| rate.try_div_int(SECONDS_PER_YEAR, Rounding::Down))
+            .map_err(ErrorCode::from)?;
+
+        self.cumulative_borrow_index = Rate::from_scaled_val(self.cumulative_borrow_index)
+            .try_mul(Rate::ONE.try_add(interest_factor).map_err(ErrorCode::from)?, Rounding::Down)
+            .map_err(ErrorCode::from)?
+            .to_scaled_val();
+
+        let interest = interest_factor
+            .try_mul_amount(self.total_borrows, Rounding::Down)
+            .map_err(ErrorCode::from)?;
+
+        self.total_borrows = self.total_borrows.checked_add(interest).ok_or(ErrorCode::InvalidAmount)?;
+        self.credit_interest(interest)?;
+        self.last_accrual_timestamp = current_time;
+
+        Ok(())
+    }
+
+    /// Split earned interest or fees between suppliers and protocol reserves
+    pub fn credit_interest(&mut self, interest: u64) -> Result<()> {
+        let reserve_interest = Decimal::from_integer(interest)
+            .try_mul(Decimal::from_scaled_val(self.reserve_factor.into()), Rounding::Down)
+            .and_then(|reserve| reserve.try_to_u64(Rounding::Down))
+            .map_err(ErrorCode::from)?;
+        self.total_deposits = self.total_deposits
+            .checked_add(interest - reserve_interest)
+            .ok_or(ErrorCode::InvalidAmount)?;
+        self.protocol_reserves = self.protocol_reserves
+            .checked_add(reserve_interest)
+            .ok_or(ErrorCode::InvalidAmount)?;
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OracleSource {
+    Pyth,
+    ChainlinkOcr2,
+    Switchboard,
+}
+
+/// How prices from the primary and secondary feeds are combined
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PriceAggregation {
+    Primary,
+    Median,
 }
 
 // Context structs
@@ -578,7 +2096,7 @@ pub struct Initialize<'info> {
     #[account(
         init,
         payer = admin,
-        space = 8 + 32 + 32 + 1 + 4 + 1,
+        space = 8 + 32 + 32 + 32 + 1 + 4 + 1,
         seeds = [b"pool"],
         bump
     )]
@@ -595,53 +2113,184 @@ pub struct AddSupportedAsset<'info> {
     #[account(
         init,
         payer = admin,
-        space = 8 + 32 + 32 + 8 + 8 + 1 + 1 + 1 + 8 + 8 + 1,
+        space = 8 + 32 + 1 + 32 + 1 + 32 + (1 + 32) + 1 + 32 + 1 + 8 + 1 + 8 + 8 + 8 + 8 + 8 + 1 + 1 + 1 + 32 + 8 + 8 + 8 + 8 + (8 * 4) + 8 + 8 + 8 + 8 + 8 + 1 + 8 + 8 + 1 + 16 + 1 + 16 + 1 + 8 + 8 + 8 + 8 + 16 + 8 + 1,
         seeds = [b"asset", mint.key().as_ref()],
         bump
     )]
     pub asset_info: Account<'info, AssetInfo>,
     pub mint: Account<'info, Mint>,
+    /// Reused when re-listing a closed reserve, as SPL mints cannot be closed
+    #[account(
+        init_if_needed,
+        payer = admin,
+        seeds = [b"shares", mint.key().as_ref()],
+        bump,
+        mint::decimals = mint.decimals,
+        mint::authority = pool,
+        constraint = share_mint.supply == 0 @ ErrorCode::AssetNotWoundDown,
+    )]
+    pub share_mint: Account<'info, Mint>,
+    /// Pool-owned account holding receipt shares while they back obligations
+    #[account(
+        init,
+        payer = admin,
+        seeds = [b"collateral", mint.key().as_ref()],
+        bump,
+        token::mint = share_mint,
+        token::authority = pool,
+    )]
+    pub collateral_escrow: Account<'info, TokenAccount>,
     #[account(mut)]
     pub admin: Signer<'info>,
+    pub token_program: Program<'info, Token>,
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+pub struct UpdateAssetConfig<'info> {
+    #[account(has_one = admin)]
+    pub pool: Account<'info, Pool>,
+    #[account(mut, seeds = [b"asset", mint.key().as_ref()], bump = asset_info.bump)]
+    pub asset_info: Account<'info, AssetInfo>,
+    pub mint: Account<'info, Mint>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseDelistedAsset<'info> {
+    #[account(mut, has_one = admin, has_one = treasury)]
+    pub pool: Account<'info, Pool>,
+    #[account(mut, close = admin, seeds = [b"asset", mint.key().as_ref()], bump = asset_info.bump)]
+    pub asset_info: Account<'info, AssetInfo>,
+    pub mint: Account<'info, Mint>,
+    #[account(
+        mut,
+        seeds = [b"collateral", mint.key().as_ref()],
+        bump,
+        token::mint = asset_info.share_mint,
+        token::authority = pool
+    )]
+    pub collateral_escrow: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = mint, token::authority = pool)]
+    pub pool_token_account: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = mint, token::authority = treasury)]
+    pub treasury_token_account: Account<'info, TokenAccount>,
+    /// CHECK: Treasury wallet configured on the pool
+    pub treasury: AccountInfo<'info>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
 #[derive(Accounts)]
 pub struct DepositAccounts<'info> {
     #[account(mut)]
     pub pool: Account<'info, Pool>,
     #[account(mut, seeds = [b"asset", mint.key().as_ref()], bump = asset_info.bump)]
     pub asset_info: Account<'info, AssetInfo>,
+    pub mint: Account<'info, Mint>,
+    #[account(mut, address = asset_info.share_mint)]
+    pub share_mint: Account<'info, Mint>,
+    #[account(mut, token::mint = share_mint)]
+    pub user_share_account: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = mint)]
+    pub user_token_account: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = mint, token::authority = pool)]
+    pub pool_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct LockCollateral<'info> {
+    pub pool: Account<'info, Pool>,
+    #[account(seeds = [b"asset", mint.key().as_ref()], bump = asset_info.bump)]
+    pub asset_info: Account<'info, AssetInfo>,
     #[account(
         init_if_needed,
         payer = user,
-        space = 8 + 32 + 8 + 8 + 8 + 8 + 8 + 8 + 1,
-        seeds = [b"position", user.key().as_ref(), mint.key().as_ref()],
+        space = Obligation::SPACE,
+        seeds = [b"obligation", user.key().as_ref()],
         bump
     )]
-    pub user_position: Account<'info, UserPosition>,
+    pub obligation: Account<'info, Obligation>,
     pub mint: Account<'info, Mint>,
-    #[account(mut)]
-    pub user_token_account: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub pool_token_account: Account<'info, TokenAccount>,
+    #[account(address = asset_info.share_mint)]
+    pub share_mint: Account<'info, Mint>,
+    #[account(mut, token::mint = share_mint)]
+    pub user_share_account: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        seeds = [b"collateral", mint.key().as_ref()],
+        bump,
+        token::mint = share_mint,
+        token::authority = pool
+    )]
+    pub collateral_escrow: Account<'info, TokenAccount>,
     #[account(mut)]
     pub user: Signer<'info>,
     pub token_program: Program<'info, Token>,
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+pub struct UnlockCollateral<'info> {
+    pub pool: Account<'info, Pool>,
+    #[account(seeds = [b"asset", mint.key().as_ref()], bump = asset_info.bump)]
+    pub asset_info: Account<'info, AssetInfo>,
+    #[account(mut, seeds = [b"obligation", user.key().as_ref()], bump = obligation.bump)]
+    pub obligation: Account<'info, Obligation>,
+    pub mint: Account<'info, Mint>,
+    #[account(seeds = [b"emode".as_ref(), &[obligation.emode_category]], bump = emode_category.bump)]
+    pub emode_category: Option<Account<'info, EModeCategory>>,
+    #[account(address = asset_info.share_mint)]
+    pub share_mint: Account<'info, Mint>,
+    #[account(mut, token::mint = share_mint)]
+    pub user_share_account: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        seeds = [b"collateral", mint.key().as_ref()],
+        bump,
+        token::mint = share_mint,
+        token::authority = pool
+    )]
+    pub collateral_escrow: Account<'info, TokenAccount>,
+    pub user: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
 #[derive(Accounts)]
 pub struct BorrowCrossChain<'info> {
     #[account(mut)]
     pub pool: Account<'info, Pool>,
     #[account(mut, seeds = [b"asset", mint.key().as_ref()], bump = asset_info.bump)]
     pub asset_info: Account<'info, AssetInfo>,
-    #[account(mut, seeds = [b"position", user.key().as_ref(), mint.key().as_ref()], bump = user_position.bump)]
-    pub user_position: Account<'info, UserPosition>,
+    #[account(mut, seeds = [b"obligation", user.key().as_ref()], bump = obligation.bump)]
+    pub obligation: Account<'info, Obligation>,
     pub mint: Account<'info, Mint>,
-    /// CHECK: Chainlink price feed account
+    /// CHECK: Must be the asset's configured oracle account, decoded in get_asset_price
+    #[account(
+        address = asset_info.price_feed @ ErrorCode::PriceFeedMismatch,
+        owner = asset_info.oracle_program @ ErrorCode::PriceFeedMismatch
+    )]
     pub price_feed: AccountInfo<'info>,
+    /// CHECK: Must be the asset's configured secondary oracle account, required when one is configured
+    #[account(
+        constraint = asset_info.secondary_price_feed == Some(secondary_price_feed.key()) @ ErrorCode::PriceFeedMismatch,
+        owner = asset_info.secondary_oracle_program @ ErrorCode::PriceFeedMismatch
+    )]
+    pub secondary_price_feed: Option<AccountInfo<'info>>,
+    #[account(seeds = [b"price_history", mint.key().as_ref()], bump = price_history.bump)]
+    pub price_history: Option<Account<'info, PriceHistory>>,
+    /// The obligation's isolated collateral reserve, when it is not the asset itself
+    #[account(
+        mut,
+        seeds = [b"asset", isolated_asset_info.mint.as_ref()],
+        bump = isolated_asset_info.bump,
+        constraint = isolated_asset_info.mint != mint.key() @ ErrorCode::IsolatedReserveMismatch
+    )]
+    pub isolated_asset_info: Option<Account<'info, AssetInfo>>,
     /// CHECK: CCIP program for cross-chain messaging
     pub ccip_program: AccountInfo<'info>,
     #[account(mut)]
@@ -649,17 +2298,65 @@ pub struct BorrowCrossChain<'info> {
 }
 
 #[derive(Accounts)]
-pub struct RepayAccounts<'info> {
+pub struct BorrowAccounts<'info> {
     #[account(mut)]
     pub pool: Account<'info, Pool>,
     #[account(mut, seeds = [b"asset", mint.key().as_ref()], bump = asset_info.bump)]
     pub asset_info: Account<'info, AssetInfo>,
-    #[account(mut, seeds = [b"position", user.key().as_ref(), mint.key().as_ref()], bump = user_position.bump)]
-    pub user_position: Account<'info, UserPosition>,
+    #[account(mut, seeds = [b"obligation", user.key().as_ref()], bump = obligation.bump)]
+    pub obligation: Account<'info, Obligation>,
     pub mint: Account<'info, Mint>,
-    #[account(mut)]
+    /// CHECK: Must be the asset's configured oracle account, decoded in get_asset_price
+    #[account(
+        address = asset_info.price_feed @ ErrorCode::PriceFeedMismatch,
+        owner = asset_info.oracle_program @ ErrorCode::PriceFeedMismatch
+    )]
+    pub price_feed: AccountInfo<'info>,
+    /// CHECK: Must be the asset's configured secondary oracle account, required when one is configured
+    #[account(
+        constraint = asset_info.secondary_price_feed == Some(secondary_price_feed.key()) @ ErrorCode::PriceFeedMismatch,
+        owner = asset_info.secondary_oracle_program @ ErrorCode::PriceFeedMismatch
+    )]
+    pub secondary_price_feed: Option<AccountInfo<'info>>,
+    #[account(seeds = [b"price_history", mint.key().as_ref()], bump = price_history.bump)]
+    pub price_history: Option<Account<'info, PriceHistory>>,
+    /// The obligation's isolated collateral reserve, when it is not the asset itself
+    #[account(
+        mut,
+        seeds = [b"asset", isolated_asset_info.mint.as_ref()],
+        bump = isolated_asset_info.bump,
+        constraint = isolated_asset_info.mint != mint.key() @ ErrorCode::IsolatedReserveMismatch
+    )]
+    pub isolated_asset_info: Option<Account<'info, AssetInfo>>,
+    #[account(mut, token::mint = mint, token::authority = pool)]
+    pub pool_token_account: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = mint)]
     pub user_token_account: Account<'info, TokenAccount>,
     #[account(mut)]
+    pub user: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct RepayAccounts<'info> {
+    #[account(mut)]
+    pub pool: Account<'info, Pool>,
+    #[account(mut, seeds = [b"asset", mint.key().as_ref()], bump = asset_info.bump)]
+    pub asset_info: Account<'info, AssetInfo>,
+    #[account(mut, seeds = [b"obligation", user.key().as_ref()], bump = obligation.bump)]
+    pub obligation: Account<'info, Obligation>,
+    pub mint: Account<'info, Mint>,
+    /// The obligation's isolated collateral reserve, when it is not the asset itself
+    #[account(
+        mut,
+        seeds = [b"asset", isolated_asset_info.mint.as_ref()],
+        bump = isolated_asset_info.bump,
+        constraint = isolated_asset_info.mint != mint.key() @ ErrorCode::IsolatedReserveMismatch
+    )]
+    pub isolated_asset_info: Option<Account<'info, AssetInfo>>,
+    #[account(mut, token::mint = mint)]
+    pub user_token_account: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = mint, token::authority = pool)]
     pub pool_token_account: Account<'info, TokenAccount>,
     #[account(mut)]
     pub user: Signer<'info>,
@@ -672,12 +2369,14 @@ pub struct WithdrawAccounts<'info> {
     pub pool: Account<'info, Pool>,
     #[account(mut, seeds = [b"asset", mint.key().as_ref()], bump = asset_info.bump)]
     pub asset_info: Account<'info, AssetInfo>,
-    #[account(mut, seeds = [b"position", user.key().as_ref(), mint.key().as_ref()], bump = user_position.bump)]
-    pub user_position: Account<'info, UserPosition>,
     pub mint: Account<'info, Mint>,
-    #[account(mut)]
+    #[account(mut, address = asset_info.share_mint)]
+    pub share_mint: Account<'info, Mint>,
+    #[account(mut, token::mint = share_mint, token::authority = user)]
+    pub user_share_account: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = mint)]
     pub user_token_account: Account<'info, TokenAccount>,
-    #[account(mut)]
+    #[account(mut, token::mint = mint, token::authority = pool)]
     pub pool_token_account: Account<'info, TokenAccount>,
     #[account(mut)]
     pub user: Signer<'info>,
@@ -688,29 +2387,140 @@ pub struct WithdrawAccounts<'info> {
 pub struct Liquidate<'info> {
     #[account(mut)]
     pub pool: Account<'info, Pool>,
-    #[account(mut, seeds = [b"position", borrower.key().as_ref(), debt_mint.key().as_ref()], bump = borrower_position.bump)]
-    pub borrower_position: Account<'info, UserPosition>,
+    #[account(mut, seeds = [b"obligation", borrower.key().as_ref()], bump = obligation.bump)]
+    pub obligation: Account<'info, Obligation>,
+    #[account(mut, seeds = [b"asset", debt_mint.key().as_ref()], bump = debt_asset_info.bump)]
+    pub debt_asset_info: Account<'info, AssetInfo>,
+    #[account(mut, seeds = [b"asset", collateral_mint.key().as_ref()], bump = collateral_asset_info.bump)]
+    pub collateral_asset_info: Account<'info, AssetInfo>,
     /// CHECK: Borrower account
     pub borrower: AccountInfo<'info>,
     pub debt_mint: Account<'info, Mint>,
     pub collateral_mint: Account<'info, Mint>,
-    #[account(mut)]
+    #[account(mut, token::mint = debt_mint)]
     pub liquidator_debt_account: Account<'info, TokenAccount>,
-    #[account(mut)]
+    #[account(mut, token::mint = collateral_mint)]
     pub liquidator_collateral_account: Account<'info, TokenAccount>,
-    #[account(mut)]
+    #[account(mut, token::mint = debt_mint, token::authority = pool)]
     pub pool_debt_account: Account<'info, TokenAccount>,
-    #[account(mut)]
+    #[account(mut, token::mint = collateral_mint, token::authority = pool)]
     pub pool_collateral_account: Account<'info, TokenAccount>,
-    /// CHECK: Chainlink price feeds
+    #[account(mut, address = collateral_asset_info.share_mint)]
+    pub collateral_share_mint: Account<'info, Mint>,
+    #[account(
+        mut,
+        seeds = [b"collateral", collateral_mint.key().as_ref()],
+        bump,
+        token::mint = collateral_share_mint,
+        token::authority = pool
+    )]
+    pub collateral_escrow: Account<'info, TokenAccount>,
+    /// CHECK: Must be the debt asset's configured oracle account
+    #[account(
+        address = debt_asset_info.price_feed @ ErrorCode::PriceFeedMismatch,
+        owner = debt_asset_info.oracle_program @ ErrorCode::PriceFeedMismatch
+    )]
     pub debt_price_feed: AccountInfo<'info>,
-    /// CHECK: Chainlink price feeds
+    /// CHECK: Must be the collateral asset's configured oracle account
+    #[account(
+        address = collateral_asset_info.price_feed @ ErrorCode::PriceFeedMismatch,
+        owner = collateral_asset_info.oracle_program @ ErrorCode::PriceFeedMismatch
+    )]
     pub collateral_price_feed: AccountInfo<'info>,
+    /// CHECK: Must be the debt asset's configured secondary oracle account
+    #[account(
+        constraint = debt_asset_info.secondary_price_feed == Some(debt_secondary_price_feed.key()) @ ErrorCode::PriceFeedMismatch,
+        owner = debt_asset_info.secondary_oracle_program @ ErrorCode::PriceFeedMismatch
+    )]
+    pub debt_secondary_price_feed: Option<AccountInfo<'info>>,
+    /// CHECK: Must be the collateral asset's configured secondary oracle account
+    #[account(
+        constraint = collateral_asset_info.secondary_price_feed == Some(collateral_secondary_price_feed.key()) @ ErrorCode::PriceFeedMismatch,
+        owner = collateral_asset_info.secondary_oracle_program @ ErrorCode::PriceFeedMismatch
+    )]
+    pub collateral_secondary_price_feed: Option<AccountInfo<'info>>,
+    #[account(seeds = [b"emode".as_ref(), &[obligation.emode_category]], bump = emode_category.bump)]
+    pub emode_category: Option<Account<'info, EModeCategory>>,
     #[account(mut)]
     pub liquidator: Signer<'info>,
     pub token_program: Program<'info, Token>,
 }
 
+#[derive(Accounts)]
+pub struct SocializeBadDebt<'info> {
+    pub pool: Account<'info, Pool>,
+    #[account(mut, seeds = [b"obligation", borrower.key().as_ref()], bump = obligation.bump)]
+    pub obligation: Account<'info, Obligation>,
+    #[account(mut, seeds = [b"asset", mint.key().as_ref()], bump = asset_info.bump)]
+    pub asset_info: Account<'info, AssetInfo>,
+    pub mint: Account<'info, Mint>,
+    /// The obligation's isolated collateral reserve, when it is not the asset itself
+    #[account(
+        mut,
+        seeds = [b"asset", isolated_asset_info.mint.as_ref()],
+        bump = isolated_asset_info.bump,
+        constraint = isolated_asset_info.mint != mint.key() @ ErrorCode::IsolatedReserveMismatch
+    )]
+    pub isolated_asset_info: Option<Account<'info, AssetInfo>>,
+    /// CHECK: Borrower account
+    pub borrower: AccountInfo<'info>,
+    pub caller: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct FlashLoan<'info> {
+    pub pool: Account<'info, Pool>,
+    #[account(mut, seeds = [b"asset", mint.key().as_ref()], bump = asset_info.bump)]
+    pub asset_info: Account<'info, AssetInfo>,
+    pub mint: Account<'info, Mint>,
+    #[account(mut, token::mint = mint, token::authority = pool)]
+    pub pool_token_account: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = mint)]
+    pub user_token_account: Account<'info, TokenAccount>,
+    pub user: Signer<'info>,
+    /// CHECK: Instructions sysvar used to find the matching flash loan instruction
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct RefreshObligation<'info> {
+    #[account(mut)]
+    pub obligation: Account<'info, Obligation>,
+    #[account(seeds = [b"emode".as_ref(), &[obligation.emode_category]], bump = emode_category.bump)]
+    pub emode_category: Option<Account<'info, EModeCategory>>,
+}
+
+#[derive(Accounts)]
+pub struct CrankPrice<'info> {
+    #[account(seeds = [b"asset", asset_info.mint.as_ref()], bump = asset_info.bump)]
+    pub asset_info: Account<'info, AssetInfo>,
+    #[account(
+        init_if_needed,
+        payer = cranker,
+        space = PriceHistory::SPACE,
+        seeds = [b"price_history", asset_info.mint.as_ref()],
+        bump
+    )]
+    pub price_history: Account<'info, PriceHistory>,
+    /// CHECK: Must be the asset's configured oracle account, decoded in get_asset_price
+    #[account(
+        address = asset_info.price_feed @ ErrorCode::PriceFeedMismatch,
+        owner = asset_info.oracle_program @ ErrorCode::PriceFeedMismatch
+    )]
+    pub price_feed: AccountInfo<'info>,
+    /// CHECK: Must be the asset's configured secondary oracle account, required when one is configured
+    #[account(
+        constraint = asset_info.secondary_price_feed == Some(secondary_price_feed.key()) @ ErrorCode::PriceFeedMismatch,
+        owner = asset_info.secondary_oracle_program @ ErrorCode::PriceFeedMismatch
+    )]
+    pub secondary_price_feed: Option<AccountInfo<'info>>,
+    #[account(mut)]
+    pub cranker: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 pub struct CCIPReceive<'info> {
     #[account(mut)]
@@ -724,6 +2534,51 @@ pub struct CCIPReceive<'info> {
     pub token_program: Program<'info, Token>,
 }
 
+#[derive(Accounts)]
+pub struct CollectProtocolFees<'info> {
+    #[account(has_one = admin, has_one = treasury)]
+    pub pool: Account<'info, Pool>,
+    #[account(mut, seeds = [b"asset", mint.key().as_ref()], bump = asset_info.bump)]
+    pub asset_info: Account<'info, AssetInfo>,
+    pub mint: Account<'info, Mint>,
+    #[account(mut, token::mint = mint, token::authority = pool)]
+    pub pool_token_account: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = mint, token::authority = treasury)]
+    pub treasury_token_account: Account<'info, TokenAccount>,
+    /// CHECK: Treasury wallet configured on the pool
+    pub treasury: AccountInfo<'info>,
+    pub admin: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(category_id: u8)]
+pub struct AddEModeCategory<'info> {
+    #[account(has_one = admin)]
+    pub pool: Account<'info, Pool>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + 1 + 32 + 8 + 8 + 8 + 1,
+        seeds = [b"emode".as_ref(), &[category_id]],
+        bump
+    )]
+    pub emode_category: Account<'info, EModeCategory>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(category_id: u8)]
+pub struct SetUserEMode<'info> {
+    #[account(mut, seeds = [b"obligation", user.key().as_ref()], bump = obligation.bump)]
+    pub obligation: Account<'info, Obligation>,
+    #[account(seeds = [b"emode".as_ref(), &[category_id]], bump = emode_category.bump)]
+    pub emode_category: Option<Account<'info, EModeCategory>>,
+    pub user: Signer<'info>,
+}
+
 #[derive(Accounts)]
 pub struct AdminAction<'info> {
     #[account(mut, has_one = admin)]
@@ -731,60 +2586,156 @@ pub struct AdminAction<'info> {
     pub admin: Signer<'info>,
 }
 
-// Events
+// Events
+#[event]
+pub struct AssetAddedEvent {
+    pub mint: Pubkey,
+    pub ltv: u64,
+    pub liquidation_threshold: u64,
+}
+
+#[event]
+pub struct AssetConfigUpdatedEvent {
+    pub mint: Pubkey,
+    pub admin: Pubkey,
+    pub old_params: AssetParams,
+    pub new_params: AssetParams,
+}
+
+#[event]
+pub struct AssetDelistedEvent {
+    pub mint: Pubkey,
+    pub liquidation_threshold: u64,
+    pub wind_down_seconds: i64,
+}
+
+#[event]
+pub struct AssetClosedEvent {
+    pub mint: Pubkey,
+    pub treasury: Pubkey,
+    pub swept_amount: u64,
+}
+
+#[event]
+pub struct DepositEvent {
+    pub user: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub shares: u64,
+    pub chain_selector: u64,
+}
+
+#[event]
+pub struct BorrowEvent {
+    pub user: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub dest_chain: u64,
+    pub health_factor: u64,
+}
+
+#[event]
+pub struct RepayEvent {
+    pub user: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct CollateralLockedEvent {
+    pub user: Pubkey,
+    pub mint: Pubkey,
+    pub shares: u64,
+}
+
+#[event]
+pub struct CollateralUnlockedEvent {
+    pub user: Pubkey,
+    pub mint: Pubkey,
+    pub shares: u64,
+}
+
+#[event]
+pub struct WithdrawEvent {
+    pub user: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub shares: u64,
+}
+
+#[event]
+pub struct LiquidationEvent {
+    pub liquidator: Pubkey,
+    pub borrower: Pubkey,
+    pub debt_amount: u64,
+    pub collateral_seized: u64,
+    pub liquidation_bonus: u64,
+    pub protocol_fee: u64, // Collateral kept as reserves out of collateral_seized
+    pub health_factor: u64,
+}
+
+#[event]
+pub struct ObligationRefreshedEvent {
+    pub owner: Pubkey,
+    pub deposited_value_usd: u128,
+    pub borrowed_value_usd: u128,
+    pub health_factor: u64,
+    pub slot: u64,
+}
+
 #[event]
-pub struct AssetAddedEvent {
+pub struct BadDebtEvent {
+    pub borrower: Pubkey,
     pub mint: Pubkey,
-    pub ltv: u64,
-    pub liquidation_threshold: u64,
+    pub amount: u64,
+    pub covered_by_reserves: u64,
+    pub socialized: u64, // Loss spread across suppliers
 }
 
 #[event]
-pub struct DepositEvent {
-    pub user: Pubkey,
+pub struct PriceDeviationEvent {
     pub mint: Pubkey,
-    pub amount: u64,
-    pub chain_selector: u64,
+    pub primary_price: u64,
+    pub secondary_price: u64,
+    pub deviation_bps: u64,
+    pub max_deviation_bps: u64,
 }
 
 #[event]
-pub struct BorrowEvent {
+pub struct FlashLoanEvent {
     pub user: Pubkey,
     pub mint: Pubkey,
     pub amount: u64,
-    pub dest_chain: u64,
-    pub health_factor: u64,
+    pub fee: u64,
 }
 
 #[event]
-pub struct RepayEvent {
+pub struct CrossChainMessageReceivedEvent {
     pub user: Pubkey,
-    pub mint: Pubkey,
+    pub action: String,
     pub amount: u64,
+    pub source_chain: u64,
 }
 
 #[event]
-pub struct WithdrawEvent {
-    pub user: Pubkey,
+pub struct ProtocolFeesCollectedEvent {
     pub mint: Pubkey,
+    pub treasury: Pubkey,
     pub amount: u64,
 }
 
 #[event]
-pub struct LiquidationEvent {
-    pub liquidator: Pubkey,
-    pub borrower: Pubkey,
-    pub debt_amount: u64,
-    pub collateral_seized: u64,
-    pub health_factor: u64,
+pub struct EModeCategoryAddedEvent {
+    pub category_id: u8,
+    pub ltv: u64,
+    pub liquidation_threshold: u64,
+    pub liquidation_bonus: u64,
 }
 
 #[event]
-pub struct CrossChainMessageReceivedEvent {
+pub struct UserEModeSetEvent {
     pub user: Pubkey,
-    pub action: String,
-    pub amount: u64,
-    pub source_chain: u64,
+    pub category_id: u8,
 }
 
 #[event]
@@ -797,89 +2748,673 @@ pub struct ProtocolUnpausedEvent {
     pub admin: Pubkey,
 }
 
-// Helper functions
-fn get_asset_price(_price_feed: &AccountInfo) -> Result<u64> {
-    // Placeholder implementation - in production, this would integrate with Chainlink
-    // For now, return a mock price (e.g., $1000 with 8 decimals)
-    Ok(100_000_000_000) // $1000 with 8 decimal places
+// Oracle adapters
+/// Price normalized from any supported oracle provider
+pub struct OraclePrice {
+    pub price: i64,
+    pub expo: i32,
+    pub conf: u64,
+    pub publish_slot: u64,
+    pub publish_time: i64,
 }
 
-fn get_bonk_config() -> (u64, u64) {
-    (BONK_LTV, BONK_LIQUIDATION_THRESHOLD)
+impl OraclePrice {
+    /// Reject stale, non-positive and low-confidence prices, then normalize to `PRICE_DECIMALS`
+    pub fn validate(&self, current_time: i64) -> Result<u64> {
+        // Reject stale prices
+        require!(self.publish_time <= current_time, ErrorCode::InvalidPriceData);
+        require!(
+            current_time - self.publish_time <= MAX_PRICE_AGE_SECONDS,
+            ErrorCode::InvalidPriceData
+        );
+
+        // Reject non-positive prices
+        require!(self.price > 0, ErrorCode::InvalidPriceData);
+        let price = self.price as u64;
+
+        // Reject prices whose confidence interval is too wide
+        let max_conf = (price as u128)
+            .checked_mul(MAX_PRICE_CONFIDENCE_BPS as u128)
+            .ok_or(ErrorCode::InvalidPriceData)?
+            / BPS_DENOMINATOR as u128;
+        require!((self.conf as u128) <= max_conf, ErrorCode::InvalidPriceData);
+
+        normalize_price(price, self.expo)
+    }
 }
 
-fn is_bonk_token(mint: &Pubkey) -> bool {
-    mint.to_string() == BONK_MINT
+/// Decodes a provider-specific oracle account into an `OraclePrice`
+pub trait OracleAdapter {
+    fn read_price(price_feed: &AccountInfo) -> Result<OraclePrice>;
 }
 
-fn calculate_usd_value(amount: u64, price: u64, decimals: u8) -> Result<u64> {
-    let amount_normalized = amount
-        .checked_mul(10_u64.pow(18_u32.saturating_sub(decimals as u32)))
-        .ok_or(ErrorCode::InvalidAmount)?;
+pub struct PythAdapter;
+pub struct ChainlinkAdapter;
+pub struct SwitchboardAdapter;
+
+impl OracleAdapter for PythAdapter {
+    fn read_price(price_feed: &AccountInfo) -> Result<OraclePrice> {
+        let data = price_feed.try_borrow_data()?;
+        require!(data.len() >= PYTH_PRICE_ACCOUNT_MIN_LEN, ErrorCode::InvalidPriceData);
+        require!(read_u32(&data, PYTH_MAGIC_OFFSET) == PYTH_MAGIC, ErrorCode::InvalidPriceData);
+        require!(
+            read_u32(&data, PYTH_ACCOUNT_TYPE_OFFSET) == PYTH_ACCOUNT_TYPE_PRICE,
+            ErrorCode::InvalidPriceData
+        );
+        require!(
+            read_u32(&data, PYTH_AGG_STATUS_OFFSET) == PYTH_STATUS_TRADING,
+            ErrorCode::InvalidPriceData
+        );
+
+        Ok(OraclePrice {
+            price: read_i64(&data, PYTH_AGG_PRICE_OFFSET),
+            expo: read_u32(&data, PYTH_EXPONENT_OFFSET) as i32,
+            conf: read_u64(&data, PYTH_AGG_CONF_OFFSET),
+            publish_slot: read_u64(&data, PYTH_AGG_PUB_SLOT_OFFSET),
+            publish_time: read_i64(&data, PYTH_TIMESTAMP_OFFSET),
+        })
+    }
+}
 
-    amount_normalized
-        .checked_mul(price)
-        .and_then(|x| x.checked_div(10_u64.pow(8))) // Price has 8 decimals
-        .ok_or(ErrorCode::InvalidAmount.into())
+impl OracleAdapter for ChainlinkAdapter {
+    fn read_price(price_feed: &AccountInfo) -> Result<OraclePrice> {
+        let data = price_feed.try_borrow_data()?;
+        require!(data.len() >= CHAINLINK_TRANSMISSIONS_OFFSET, ErrorCode::InvalidPriceData);
+
+        let live_length = read_u32(&data, CHAINLINK_LIVE_LENGTH_OFFSET) as usize;
+        let live_cursor = read_u32(&data, CHAINLINK_LIVE_CURSOR_OFFSET) as usize;
+        require!(live_length > 0, ErrorCode::InvalidPriceData);
+
+        // The live cursor points at the next slot to be written
+        let latest = (live_cursor + live_length - 1) % live_length;
+        let offset = CHAINLINK_TRANSMISSIONS_OFFSET + latest * CHAINLINK_TRANSMISSION_SIZE;
+        require!(data.len() >= offset + CHAINLINK_TRANSMISSION_SIZE, ErrorCode::InvalidPriceData);
+
+        let answer = read_i128(&data, offset + CHAINLINK_TRANSMISSION_ANSWER_OFFSET);
+        let decimals = data[CHAINLINK_DECIMALS_OFFSET];
+
+        Ok(OraclePrice {
+            price: i64::try_from(answer).map_err(|_| ErrorCode::InvalidPriceData)?,
+            expo: -(decimals as i32),
+            conf: 0, // OCR2 reports carry no confidence interval
+            publish_slot: read_u64(&data, offset + CHAINLINK_TRANSMISSION_SLOT_OFFSET),
+            publish_time: read_u32(&data, offset + CHAINLINK_TRANSMISSION_TIMESTAMP_OFFSET) as i64,
+        })
+    }
 }
 
-fn calculate_health_factor(
-    total_collateral_value_usd: u64,
-    total_borrow_value_usd: u64,
-    liquidation_threshold: u64,
-) -> Result<u64> {
-    if total_borrow_value_usd == 0 {
-        return Ok(u64::MAX); // No debt means infinite health factor
+impl OracleAdapter for SwitchboardAdapter {
+    fn read_price(price_feed: &AccountInfo) -> Result<OraclePrice> {
+        let data = price_feed.try_borrow_data()?;
+        require!(data.len() >= SWITCHBOARD_AGGREGATOR_MIN_LEN, ErrorCode::InvalidPriceData);
+        require!(read_u32(&data, SWITCHBOARD_NUM_SUCCESS_OFFSET) > 0, ErrorCode::InvalidPriceData);
+
+        let mantissa = read_i128(&data, SWITCHBOARD_RESULT_OFFSET);
+        let scale = read_u32(&data, SWITCHBOARD_RESULT_OFFSET + 16);
+        let std_mantissa = read_i128(&data, SWITCHBOARD_STD_DEVIATION_OFFSET).unsigned_abs();
+        let std_scale = read_u32(&data, SWITCHBOARD_STD_DEVIATION_OFFSET + 16);
+
+        // Express the standard deviation in the result's scale
+        let conf = if scale >= std_scale {
+            10_u128
+                .checked_pow(scale - std_scale)
+                .and_then(|factor| std_mantissa.checked_mul(factor))
+        } else {
+            10_u128
+                .checked_pow(std_scale - scale)
+                .map(|factor| std_mantissa / factor)
+        }
+        .and_then(|conf| u64::try_from(conf).ok())
+        .ok_or(ErrorCode::InvalidPriceData)?;
+
+        Ok(OraclePrice {
+            price: i64::try_from(mantissa).map_err(|_| ErrorCode::InvalidPriceData)?,
+            expo: -(scale as i32),
+            conf,
+            publish_slot: read_u64(&data, SWITCHBOARD_ROUND_OPEN_SLOT_OFFSET),
+            publish_time: read_i64(&data, SWITCHBOARD_ROUND_OPEN_TIMESTAMP_OFFSET),
+        })
     }
+}
 
-    let adjusted_collateral = total_collateral_value_usd
-        .checked_mul(liquidation_threshold)
-        .ok_or(ErrorCode::InvalidAmount)?
-        .checked_div(PRECISION)
-        .ok_or(ErrorCode::InvalidAmount)?;
+impl OracleSource {
+    pub fn read_price(&self, price_feed: &AccountInfo) -> Result<OraclePrice> {
+        match self {
+            OracleSource::Pyth => PythAdapter::read_price(price_feed),
+            OracleSource::ChainlinkOcr2 => ChainlinkAdapter::read_price(price_feed),
+            OracleSource::Switchboard => SwitchboardAdapter::read_price(price_feed),
+        }
+    }
+}
 
-    adjusted_collateral
-        .checked_mul(PRECISION)
-        .ok_or(ErrorCode::InvalidAmount)?
-        .checked_div(total_borrow_value_usd)
-        .ok_or(ErrorCode::InvalidAmount.into())
+// Helper functions
+/// Outcome of reading an asset's oracle sources
+enum AssetPrice {
+    Valid(u64),
+    /// The primary and secondary sources disagree by more than the asset allows
+    Deviated(PriceDeviationEvent),
 }
 
-fn calculate_liquidation_amount(
-    debt_amount: u64,
-    debt_price: u64,
-    collateral_price: u64,
-    liquidation_bonus: u64,
+/// Price an asset, failing when its sources disagree. Deviation events are
+/// only emitted by `crank_price`, since an event logged by a failing
+/// instruction is rolled back with it.
+fn get_asset_price(
+    asset_info: &AssetInfo,
+    price_feed: &AccountInfo,
+    secondary_price_feed: Option<&AccountInfo>,
 ) -> Result<u64> {
-    let debt_value = debt_amount
-        .checked_mul(debt_price)
-        .ok_or(ErrorCode::InvalidAmount)?;
+    match read_asset_price(asset_info, price_feed, secondary_price_feed)? {
+        AssetPrice::Valid(price) => Ok(price),
+        AssetPrice::Deviated(_) => Err(ErrorCode::PriceDeviationTooHigh.into()),
+    }
+}
+
+fn read_asset_price(
+    asset_info: &AssetInfo,
+    price_feed: &AccountInfo,
+    secondary_price_feed: Option<&AccountInfo>,
+) -> Result<AssetPrice> {
+    let primary_price = get_oracle_price(asset_info.oracle_source, price_feed)?;
 
-    let collateral_value_needed = debt_value
-        .checked_mul(PRECISION.checked_add(liquidation_bonus).unwrap())
-        .ok_or(ErrorCode::InvalidAmount)?
-        .checked_div(PRECISION)
-        .ok_or(ErrorCode::InvalidAmount)?;
+    if asset_info.secondary_price_feed.is_none() {
+        return Ok(AssetPrice::Valid(primary_price));
+    }
+
+    let secondary_price_feed = secondary_price_feed.ok_or(ErrorCode::SecondaryPriceFeedMissing)?;
+    let secondary_price = get_oracle_price(asset_info.secondary_oracle_source, secondary_price_feed)?;
 
-    collateral_value_needed
-        .checked_div(collateral_price)
-        .ok_or(ErrorCode::InvalidAmount.into())
+    Ok(aggregate_prices(asset_info, primary_price, secondary_price))
 }
 
-fn update_health_factor(
-    user_position: &mut UserPosition,
-    _remaining_accounts: &[AccountInfo],
+fn aggregate_prices(asset_info: &AssetInfo, primary_price: u64, secondary_price: u64) -> AssetPrice {
+    // Refuse to price the asset when the sources disagree
+    let deviation_bps = primary_price.abs_diff(secondary_price) as u128
+        * BPS_DENOMINATOR as u128
+        / primary_price.min(secondary_price) as u128;
+    let deviation_bps = u64::try_from(deviation_bps).unwrap_or(u64::MAX);
+
+    if deviation_bps > asset_info.max_price_deviation_bps {
+        return AssetPrice::Deviated(PriceDeviationEvent {
+            mint: asset_info.mint,
+            primary_price,
+            secondary_price,
+            deviation_bps,
+            max_deviation_bps: asset_info.max_price_deviation_bps,
+        });
+    }
+
+    match asset_info.price_aggregation {
+        PriceAggregation::Primary => AssetPrice::Valid(primary_price),
+        // The median of two sources is their midpoint
+        PriceAggregation::Median => AssetPrice::Valid(((primary_price as u128 + secondary_price as u128) / 2) as u64),
+    }
+}
+
+/// Value collateral at min(spot, TWAP) when the asset opts into TWAP pricing
+fn collateral_price(spot_price: u64, twap: Option<u64>) -> u64 {
+    twap.map_or(spot_price, |twap| spot_price.min(twap))
+}
+
+/// Value debt at max(spot, TWAP) when the asset opts into TWAP pricing
+fn debt_price(spot_price: u64, twap: Option<u64>) -> u64 {
+    twap.map_or(spot_price, |twap| spot_price.max(twap))
+}
+
+fn twap_price(
+    asset_info: &AssetInfo,
+    price_history: Option<&PriceHistory>,
+    current_time: i64,
+) -> Result<Option<u64>> {
+    if !asset_info.use_twap {
+        return Ok(None);
+    }
+
+    let price_history = price_history.ok_or(ErrorCode::PriceHistoryUnavailable)?;
+    require!(price_history.len > 0, ErrorCode::PriceHistoryUnavailable);
+    require!(
+        current_time - price_history.last_update_timestamp <= MAX_PRICE_HISTORY_AGE_SECONDS,
+        ErrorCode::PriceHistoryUnavailable
+    );
+
+    Ok(Some(price_history.twap))
+}
+
+/// A reserve loaded from remaining accounts together with its oracle prices
+struct ReserveQuote {
+    reserve: AssetInfo,
+    price: u64,
+    twap: Option<u64>,
+}
+
+/// Load and price `count` reserves from remaining accounts, advancing past the accounts used
+fn load_reserve_quotes(
+    remaining_accounts: &mut &[AccountInfo],
+    count: usize,
+    current_time: i64,
+) -> Result<Vec<ReserveQuote>> {
+    let mut quotes = Vec::with_capacity(count);
+    for _ in 0..count {
+        let asset_info = next_account(remaining_accounts)?;
+        require_keys_eq!(*asset_info.owner, crate::ID, ErrorCode::AssetNotSupported);
+        let mut reserve = AssetInfo::try_deserialize(&mut &asset_info.try_borrow_data()?[..])?;
+        // Value debt at the index it will have once interest is accrued
+        reserve.accrue_interest(current_time)?;
+
+        let price_feed = next_account(remaining_accounts)?;
+        require_keys_eq!(price_feed.key(), reserve.price_feed, ErrorCode::PriceFeedMismatch);
+        require_keys_eq!(*price_feed.owner, reserve.oracle_program, ErrorCode::PriceFeedMismatch);
+
+        let secondary_price_feed = match reserve.secondary_price_feed {
+            Some(secondary_key) => {
+                let secondary_price_feed = next_account(remaining_accounts)?;
+                require_keys_eq!(secondary_price_feed.key(), secondary_key, ErrorCode::PriceFeedMismatch);
+                require_keys_eq!(
+                    *secondary_price_feed.owner,
+                    reserve.secondary_oracle_program,
+                    ErrorCode::PriceFeedMismatch
+                );
+                Some(secondary_price_feed)
+            }
+            None => None,
+        };
+        let price = get_asset_price(&reserve, price_feed, secondary_price_feed)?;
+
+        let price_history = if reserve.use_twap {
+            let price_history = next_account(remaining_accounts)?;
+            require_keys_eq!(*price_history.owner, crate::ID, ErrorCode::PriceHistoryUnavailable);
+            let price_history = PriceHistory::try_deserialize(&mut &price_history.try_borrow_data()?[..])?;
+            require_keys_eq!(price_history.mint, reserve.mint, ErrorCode::PriceHistoryUnavailable);
+            Some(price_history)
+        } else {
+            None
+        };
+        let twap = twap_price(&reserve, price_history.as_ref(), current_time)?;
+
+        quotes.push(ReserveQuote { reserve, price, twap });
+    }
+    Ok(quotes)
+}
+
+fn next_account<'a, 'info>(accounts: &mut &'a [AccountInfo<'info>]) -> Result<&'a AccountInfo<'info>> {
+    let (account, rest) = accounts.split_first().ok_or(ErrorCode::MissingReserveAccount)?;
+    *accounts = rest;
+    Ok(account)
+}
+
+fn find_quote(quotes: &[ReserveQuote], mint: Pubkey) -> Result<&ReserveQuote> {
+    quotes
+        .iter()
+        .find(|quote| quote.reserve.mint == mint)
+        .ok_or(ErrorCode::MissingReserveAccount.into())
+}
+
+/// Reprice every deposit and borrow, then refresh the obligation's totals and health factor
+fn value_obligation(
+    obligation: &mut Obligation,
+    quotes: &[ReserveQuote],
+    emode_category: Option<&EModeCategory>,
 ) -> Result<()> {
-    // Simplified implementation - in production, this would calculate based on all positions
-    if user_position.total_borrow_value_usd == 0 {
-        user_position.health_factor = u64::MAX;
+    let position_category = obligation.emode_category;
+    obligation_emode(position_category, emode_category)?;
+
+    // Collateral is valued down and debt up, so rounding never flatters the obligation
+    let mut values = ObligationValues::default();
+    for deposit in obligation.deposits.iter_mut() {
+        let quote = find_quote(quotes, deposit.mint)?;
+        let reserve = &quote.reserve;
+        let amount = reserve.shares_to_amount(deposit.shares)?;
+        let value = calculate_usd_value(
+            amount,
+            collateral_price(quote.price, quote.twap),
+            reserve.decimals,
+            Rounding::Down,
+        )
+        .map_err(ErrorCode::from)?;
+
+        let emode = active_emode(position_category, &[reserve], emode_category)?;
+        let ltv = emode.map_or(reserve.ltv, |category| category.ltv);
+        let liquidation_threshold = emode.map_or(reserve.liquidation_threshold, |category| category.liquidation_threshold);
+
+        deposit.market_value_usd = value.to_scaled_val();
+        values
+            .add_collateral(value, ltv, liquidation_threshold)
+            .map_err(ErrorCode::from)?;
+    }
+
+    for borrow in obligation.borrows.iter_mut() {
+        let quote = find_quote(quotes, borrow.mint)?;
+        let reserve = &quote.reserve;
+        require!(
+            position_category == 0 || reserve.emode_category == position_category,
+            ErrorCode::EModeCategoryMismatch
+        );
+        let debt = scaled_to_debt(borrow.scaled_borrow_balance, reserve.cumulative_borrow_index)?;
+        let value = calculate_usd_value(
+            debt,
+            debt_price(quote.price, quote.twap),
+            reserve.decimals,
+            Rounding::Up,
+        )
+        .map_err(ErrorCode::from)?;
+
+        borrow.market_value_usd = value.to_scaled_val();
+        values.add_borrow(value).map_err(ErrorCode::from)?;
+    }
+
+    obligation.set_values(values);
+    obligation.update_health_factor()
+}
+
+fn get_oracle_price(oracle_source: OracleSource, price_feed: &AccountInfo) -> Result<u64> {
+    let oracle_price = oracle_source.read_price(price_feed)?;
+    oracle_price.validate(Clock::get()?.unix_timestamp)
+}
+
+/// Rescale a price with exponent `expo` to `PRICE_DECIMALS` decimals
+fn normalize_price(price: u64, expo: i32) -> Result<u64> {
+    let target_expo = -(PRICE_DECIMALS as i32);
+    let normalized = if expo >= target_expo {
+        10_u64
+            .checked_pow((expo - target_expo) as u32)
+            .and_then(|scale| price.checked_mul(scale))
     } else {
-        user_position.health_factor = calculate_health_factor(
-            user_position.total_collateral_value_usd,
-            user_position.total_borrow_value_usd,
-            LIQUIDATION_THRESHOLD,
-        )?;
+        10_u64
+            .checked_pow((target_expo - expo) as u32)
+            .and_then(|scale| price.checked_div(scale))
+    }
+    .ok_or(ErrorCode::InvalidPriceData)?;
+
+    require!(normalized > 0, ErrorCode::InvalidPriceData);
+    Ok(normalized)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_i64(data: &[u8], offset: usize) -> i64 {
+    i64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_i128(data: &[u8], offset: usize) -> i128 {
+    i128::from_le_bytes(data[offset..offset + 16].try_into().unwrap())
+}
+
+/// Check that a top-level flash_borrow at `current_index` is followed by exactly the flash_repay
+/// for it, with no other flash_borrow in between. `load_instruction` reads the instructions sysvar.
+fn check_flash_borrow(
+    stack_height: usize,
+    current_index: usize,
+    amount: u64,
+    asset_info: Pubkey,
+    load_instruction: impl Fn(usize) -> Option<Instruction>,
+) -> Result<()> {
+    require!(stack_height == TRANSACTION_LEVEL_STACK_HEIGHT, ErrorCode::InvalidFlashLoan);
+
+    let mut index = current_index + 1;
+    while let Some(ix) = load_instruction(index) {
+        if ix.program_id == crate::ID {
+            if ix.data.starts_with(instruction::FlashBorrow::DISCRIMINATOR) {
+                // Nested or sequential flash borrows are not supported
+                return Err(ErrorCode::InvalidFlashLoan.into());
+            }
+            if ix.data.starts_with(instruction::FlashRepay::DISCRIMINATOR) {
+                let repay = instruction::FlashRepay::try_from_slice(
+                    &ix.data[instruction::FlashRepay::DISCRIMINATOR.len()..],
+                )?;
+                require!(
+                    repay.amount == amount && repay.borrow_instruction_index as usize == current_index,
+                    ErrorCode::InvalidFlashLoan
+                );
+                // Accounts are ordered as in FlashLoan, asset_info comes second
+                require!(
+                    ix.accounts.get(1).map(|meta| meta.pubkey) == Some(asset_info),
+                    ErrorCode::InvalidFlashLoan
+                );
+                return Ok(());
+            }
+        }
+        index += 1;
+    }
+    Err(ErrorCode::FlashLoanNotRepaid.into())
+}
+
+/// Check that a top-level flash_repay at `current_index` settles the flash_borrow at
+/// `borrow_instruction_index`, and that no other repay in the transaction settles it too
+fn check_flash_repay(
+    stack_height: usize,
+    current_index: usize,
+    amount: u64,
+    borrow_instruction_index: u8,
+    asset_info: Pubkey,
+    load_instruction: impl Fn(usize) -> Option<Instruction>,
+) -> Result<()> {
+    require!(stack_height == TRANSACTION_LEVEL_STACK_HEIGHT, ErrorCode::InvalidFlashLoan);
+    require!((borrow_instruction_index as usize) < current_index, ErrorCode::InvalidFlashLoan);
+
+    let borrow_ix = load_instruction(borrow_instruction_index as usize).ok_or(ErrorCode::InvalidFlashLoan)?;
+    require_keys_eq!(borrow_ix.program_id, crate::ID, ErrorCode::InvalidFlashLoan);
+    require!(
+        borrow_ix.data.starts_with(instruction::FlashBorrow::DISCRIMINATOR),
+        ErrorCode::InvalidFlashLoan
+    );
+    let borrow = instruction::FlashBorrow::try_from_slice(
+        &borrow_ix.data[instruction::FlashBorrow::DISCRIMINATOR.len()..],
+    )?;
+    require!(borrow.amount == amount, ErrorCode::InvalidFlashLoan);
+    require!(
+        borrow_ix.accounts.get(1).map(|meta| meta.pubkey) == Some(asset_info),
+        ErrorCode::InvalidFlashLoan
+    );
+
+    let mut index = 0;
+    while let Some(ix) = load_instruction(index) {
+        if index != current_index
+            && ix.program_id == crate::ID
+            && ix.data.starts_with(instruction::FlashRepay::DISCRIMINATOR)
+        {
+            let repay = instruction::FlashRepay::try_from_slice(
+                &ix.data[instruction::FlashRepay::DISCRIMINATOR.len()..],
+            )?;
+            require!(
+                repay.borrow_instruction_index != borrow_instruction_index,
+                ErrorCode::InvalidFlashLoan
+            );
+        }
+        index += 1;
+    }
+    Ok(())
+}
+
+/// Current debt for a scaled balance, rounded up in the protocol's favour
+fn scaled_to_debt(scaled_balance: u64, borrow_index: u128) -> Result<u64> {
+    Ok(Rate::from_scaled_val(borrow_index)
+        .try_mul_amount(scaled_balance, Rounding::Up)
+        .map_err(ErrorCode::from)?)
+}
+
+/// Scaled balance for an amount of debt at the given borrow index
+fn debt_to_scaled(amount: u64, borrow_index: u128, rounding: Rounding) -> Result<u64> {
+    Ok(Rate::from_scaled_val(borrow_index)
+        .try_div_amount(amount, rounding)
+        .map_err(ErrorCode::from)?)
+}
+
+/// The obligation's e-mode category account, which must be passed whenever the obligation is in e-mode
+fn obligation_emode(
+    position_category: u8,
+    emode_category: Option<&EModeCategory>,
+) -> Result<Option<&EModeCategory>> {
+    if position_category == 0 {
+        return Ok(None);
+    }
+    let category = emode_category.ok_or(ErrorCode::EModeCategoryMissing)?;
+    require!(category.id == position_category, ErrorCode::EModeCategoryMismatch);
+    Ok(Some(category))
+}
+
+/// The position's e-mode category, if every asset involved belongs to it and none is being delisted.
+/// Only the assets can turn e-mode off; a missing category account is an error, not base weights.
+fn active_emode<'a>(
+    position_category: u8,
+    assets: &[&AssetInfo],
+    emode_category: Option<&'a EModeCategory>,
+) -> Result<Option<&'a EModeCategory>> {
+    let category = obligation_emode(position_category, emode_category)?;
+    Ok(category.filter(|_| {
+        assets
+            .iter()
+            .all(|asset| asset.emode_category == position_category && !asset.is_delisted())
+    }))
+}
+
+/// Check a new borrow against a refreshed obligation and record it on the obligation and reserve
+fn record_borrow(
+    obligation: &mut Obligation,
+    asset_info: &mut AssetInfo,
+    isolated_asset_info: Option<&mut AssetInfo>,
+    amount: u64,
+    borrow_value_usd: Decimal,
+    current_slot: u64,
+) -> Result<()> {
+    // Enforce the borrow cap
+    require!(
+        asset_info.borrow_cap == 0
+            || asset_info.total_borrows.checked_add(amount).unwrap() <= asset_info.borrow_cap,
+        ErrorCode::BorrowCapExceeded
+    );
+
+    // Risk checks use the values from a refresh in this slot
+    obligation.require_fresh(current_slot)?;
+
+    // Check LTV against the refreshed collateral
+    let mut values = obligation.values();
+    require!(
+        values.can_borrow(borrow_value_usd).map_err(ErrorCode::from)?,
+        ErrorCode::InsufficientCollateral
+    );
+
+    // Record the debt, rounding the scaled amount up in the protocol's favour
+    let mint = asset_info.mint;
+    let scaled_amount = debt_to_scaled(amount, asset_info.cumulative_borrow_index, Rounding::Up)?;
+    let entry = match obligation.find_borrow(mint) {
+        Some(index) => index,
+        None => {
+            require!(
+                obligation.borrows.len() < MAX_OBLIGATION_BORROWS,
+                ErrorCode::ObligationReserveLimit
+            );
+            obligation.borrows.push(ObligationLiquidity {
+                mint,
+                scaled_borrow_balance: 0,
+                isolated_debt_usd: 0,
+                market_value_usd: 0,
+            });
+            obligation.borrows.len() - 1
+        }
+    };
+    let borrow = &mut obligation.borrows[entry];
+    borrow.scaled_borrow_balance = borrow.scaled_borrow_balance
+        .checked_add(scaled_amount)
+        .unwrap();
+    asset_info.total_scaled_borrows = asset_info.total_scaled_borrows
+        .checked_add(scaled_amount)
+        .unwrap();
+    borrow.market_value_usd = Decimal::from_scaled_val(borrow.market_value_usd)
+        .try_add(borrow_value_usd)
+        .map_err(ErrorCode::from)?
+        .to_scaled_val();
+    values.add_borrow(borrow_value_usd).map_err(ErrorCode::from)?;
+    obligation.set_values(values);
+    obligation.update_health_factor()?;
+    require!(obligation.health_factor >= MIN_HEALTH_FACTOR, ErrorCode::HealthFactorTooLow);
+
+    // Borrows against isolated collateral count toward its debt ceiling
+    let borrowable_in_isolation = asset_info.borrowable_in_isolation;
+    if let Some(isolated_reserve) = isolated_reserve(obligation.isolated_mint, asset_info, isolated_asset_info)? {
+        add_isolated_debt(isolated_reserve, borrowable_in_isolation, borrow_value_usd)?;
+        let borrow = &mut obligation.borrows[entry];
+        borrow.isolated_debt_usd = Decimal::from_scaled_val(borrow.isolated_debt_usd)
+            .try_add(borrow_value_usd)
+            .map_err(ErrorCode::from)?
+            .to_scaled_val();
+    }
+
+    asset_info.total_borrows = asset_info.total_borrows
+        .checked_add(amount)
+        .unwrap();
+
+    Ok(())
+}
+
+/// The reserve whose debt ceiling backs the obligation's borrows, if its collateral is isolated
+fn isolated_reserve<'a>(
+    isolated_mint: Option<Pubkey>,
+    asset_info: &'a mut AssetInfo,
+    isolated_asset_info: Option<&'a mut AssetInfo>,
+) -> Result<Option<&'a mut AssetInfo>> {
+    let isolated_mint = match isolated_mint {
+        Some(isolated_mint) => isolated_mint,
+        None => return Ok(None),
+    };
+    if asset_info.mint == isolated_mint {
+        return Ok(Some(asset_info));
+    }
+
+    let isolated_asset_info = isolated_asset_info.ok_or(ErrorCode::IsolatedReserveMismatch)?;
+    require_keys_eq!(isolated_asset_info.mint, isolated_mint, ErrorCode::IsolatedReserveMismatch);
+    Ok(Some(isolated_asset_info))
+}
+
+/// Enforce isolation mode when borrowing against isolated collateral
+fn add_isolated_debt(
+    collateral_asset: &mut AssetInfo,
+    borrowable_in_isolation: bool,
+    borrow_value_usd: Decimal,
+) -> Result<()> {
+    require!(borrowable_in_isolation, ErrorCode::IsolationModeBorrowNotAllowed);
+    let new_isolated_debt = Decimal::from_scaled_val(collateral_asset.isolated_debt_usd)
+        .try_add(borrow_value_usd)
+        .map_err(|_| ErrorCode::DebtCeilingExceeded)?;
+    require!(
+        new_isolated_debt <= Decimal::from_scaled_val(collateral_asset.debt_ceiling_usd),
+        ErrorCode::DebtCeilingExceeded
+    );
+    collateral_asset.isolated_debt_usd = new_isolated_debt.to_scaled_val();
+
+    Ok(())
+}
+
+/// Release the share of a borrow's isolated debt covered by a repayment, rounded down
+/// so the debt ceiling is never freed early
+fn release_isolated_debt(
+    borrow: &mut ObligationLiquidity,
+    collateral_asset: &mut AssetInfo,
+    repaid_amount: u64,
+    current_debt: u64,
+) -> Result<()> {
+    if borrow.isolated_debt_usd == 0 || current_debt == 0 {
+        return Ok(());
     }
+
+    let released = if repaid_amount >= current_debt {
+        borrow.isolated_debt_usd
+    } else {
+        pro_rata_value(
+            Decimal::from_scaled_val(borrow.isolated_debt_usd),
+            repaid_amount,
+            current_debt,
+            Rounding::Down,
+        )
+        .map_err(ErrorCode::from)?
+        .to_scaled_val()
+    };
+    borrow.isolated_debt_usd -= released;
+    collateral_asset.isolated_debt_usd = collateral_asset.isolated_debt_usd.saturating_sub(released);
     Ok(())
 }
 
@@ -909,3 +3444,629 @@ fn mint_synthetic_asset(
     msg!("Synthetic asset would be minted here");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::solana_program::instruction::AccountMeta;
+    use anchor_lang::InstructionData;
+
+    const WAD: u64 = PRECISION;
+
+    /// A listed $1 stablecoin reserve with 6 decimals in e-mode category 1
+    fn reserve(mint: Pubkey) -> AssetInfo {
+        AssetInfo {
+            mint,
+            decimals: 6,
+            price_feed: Pubkey::new_unique(),
+            oracle_source: OracleSource::Pyth,
+            oracle_program: Pubkey::new_unique(),
+            secondary_price_feed: None,
+            secondary_oracle_source: OracleSource::Pyth,
+            secondary_oracle_program: Pubkey::default(),
+            price_aggregation: PriceAggregation::Primary,
+            max_price_deviation_bps: 0,
+            use_twap: false,
+            ltv: WAD / 100 * 75,
+            liquidation_threshold: WAD / 100 * 80,
+            liquidation_bonus: WAD / 100 * 10,
+            protocol_liquidation_fee: 0,
+            liquidation_auction_slots: 0,
+            is_active: true,
+            can_be_collateral: true,
+            can_be_borrowed: true,
+            share_mint: Pubkey::new_unique(),
+            total_deposits: 0,
+            total_borrows: 0,
+            total_scaled_borrows: 0,
+            total_shares: 0,
+            interest_rate_model: InterestRateModel {
+                base_rate: WAD / 100 * 2,
+                slope1: WAD / 100 * 4,
+                optimal_utilization: WAD / 100 * 80,
+                slope2: WAD,
+            },
+            reserve_factor: WAD / 10,
+            protocol_reserves: 0,
+            flash_loan_fee: 0,
+            supply_cap: 0,
+            borrow_cap: 0,
+            risk_tier: RiskTier::Stable,
+            min_deposit_amount: 0,
+            min_borrow_amount: 0,
+            isolated: false,
+            debt_ceiling_usd: 0,
+            borrowable_in_isolation: false,
+            isolated_debt_usd: 0,
+            emode_category: 1,
+            close_factor: WAD / 2,
+            delist_timestamp: 0,
+            delist_wind_down_seconds: 0,
+            delist_liquidation_threshold: 0,
+            cumulative_borrow_index: INITIAL_BORROW_INDEX,
+            last_accrual_timestamp: 0,
+            bump: 0,
+        }
+    }
+
+    fn stablecoin_emode() -> EModeCategory {
+        EModeCategory {
+            id: 1,
+            label: [0; 32],
+            ltv: WAD / 100 * 93,
+            liquidation_threshold: WAD / 100 * 95,
+            liquidation_bonus: WAD / 100 * 2,
+            bump: 0,
+        }
+    }
+
+    /// An e-mode obligation with 1000 of collateral and 900 of debt in the same reserve:
+    /// healthy at the e-mode threshold of 95%, unhealthy at the base threshold of 80%
+    fn emode_obligation(reserve: &mut AssetInfo) -> Obligation {
+        reserve.total_deposits = 1_000_000_000;
+        reserve.total_shares = 1_000_000_000;
+        reserve.total_borrows = 900_000_000;
+        reserve.total_scaled_borrows = 900_000_000;
+        Obligation {
+            owner: Pubkey::new_unique(),
+            deposits: vec![ObligationCollateral { mint: reserve.mint, shares: 1_000_000_000, market_value_usd: 0 }],
+            borrows: vec![ObligationLiquidity {
+                mint: reserve.mint,
+                scaled_borrow_balance: 900_000_000,
+                isolated_debt_usd: 0,
+                market_value_usd: 0,
+            }],
+            isolated_mint: None,
+            emode_category: 1,
+            deposited_value_usd: 0,
+            borrowed_value_usd: 0,
+            allowed_borrow_value_usd: 0,
+            unhealthy_borrow_value_usd: 0,
+            health_factor: u64::MAX,
+            last_update_slot: 0,
+            liquidatable_since_slot: 0,
+            last_action_timestamp: 0,
+            bump: 0,
+        }
+    }
+
+    fn quote(reserve: &AssetInfo) -> ReserveQuote {
+        ReserveQuote { reserve: reserve.clone(), price: 100_000_000, twap: None }
+    }
+
+    #[test]
+    fn refresh_without_the_emode_account_cannot_open_a_liquidation() {
+        let mut reserve = reserve(Pubkey::new_unique());
+        let mut obligation = emode_obligation(&mut reserve);
+        let category = stablecoin_emode();
+        let quotes = [quote(&reserve)];
+
+        value_obligation(&mut obligation, &quotes, Some(&category)).unwrap();
+        assert!(obligation.health_factor >= MIN_HEALTH_FACTOR);
+
+        // Omitting the account used to fall back to the 80% base threshold and the 10% base bonus
+        assert_eq!(
+            value_obligation(&mut obligation, &quotes, None).err(),
+            Some(ErrorCode::EModeCategoryMissing.into())
+        );
+        assert_eq!(
+            active_emode(obligation.emode_category, &[&reserve, &reserve], None).err(),
+            Some(ErrorCode::EModeCategoryMissing.into())
+        );
+        assert!(obligation.health_factor >= MIN_HEALTH_FACTOR);
+
+        // A category account for another id is rejected too
+        let other = EModeCategory { id: 2, ..stablecoin_emode() };
+        assert_eq!(
+            value_obligation(&mut obligation, &quotes, Some(&other)).err(),
+            Some(ErrorCode::EModeCategoryMismatch.into())
+        );
+    }
+
+    #[test]
+    fn withdraw_removes_collateral_at_the_weights_it_was_valued_with() {
+        let mut reserve = reserve(Pubkey::new_unique());
+        let mut obligation = emode_obligation(&mut reserve);
+        let category = stablecoin_emode();
+        value_obligation(&mut obligation, &[quote(&reserve)], Some(&category)).unwrap();
+
+        // Withdrawing without the account would have subtracted at the base weights,
+        // leaving phantom threshold-weighted collateral behind
+        assert_eq!(
+            active_emode(obligation.emode_category, &[&reserve], None).err(),
+            Some(ErrorCode::EModeCategoryMissing.into())
+        );
+
+        let emode = active_emode(obligation.emode_category, &[&reserve], Some(&category)).unwrap().unwrap();
+        obligation
+            .remove_collateral(0, 100_000_000, emode.ltv, emode.liquidation_threshold)
+            .unwrap();
+        obligation.update_health_factor().unwrap();
+        assert!(obligation.health_factor < MIN_HEALTH_FACTOR);
+        assert_eq!(obligation.unhealthy_borrow_value_usd, Decimal::from_integer(855).to_scaled_val());
+    }
+
+    #[test]
+    fn deposits_are_blocked_once_bad_debt_wipes_out_the_reserve() {
+        let mut reserve = reserve(Pubkey::new_unique());
+        reserve.total_deposits = 1_000_000_000;
+        reserve.total_shares = 1_000_000_000;
+        reserve.total_borrows = 1_100_000_000; // Suppliers' liquidity and the protocol reserves, all lent out
+        reserve.total_scaled_borrows = 1_100_000_000;
+        reserve.protocol_reserves = 100_000_000;
+
+        // Reserves absorb the first 100, the rest lowers the exchange rate
+        assert_eq!(reserve.write_off_debt(400_000_000, 400_000_000), (100_000_000, 300_000_000));
+        assert_eq!(reserve.total_deposits, 700_000_000);
+        assert_eq!(reserve.amount_to_shares(700_000_000, Rounding::Down).unwrap(), 1_000_000_000);
+
+        // Socializing the rest of the debt leaves the shares with nothing behind them
+        assert_eq!(reserve.write_off_debt(700_000_000, 700_000_000), (0, 700_000_000));
+        assert_eq!(reserve.total_deposits, 0);
+        assert_eq!(reserve.total_borrows, 0);
+        assert_eq!(reserve.total_scaled_borrows, 0);
+        assert_eq!(reserve.total_shares, 1_000_000_000);
+
+        // A new deposit would otherwise mint 1:1 and hand most of it to the old shareholders
+        assert_eq!(
+            reserve.amount_to_shares(1_000_000, Rounding::Down).err(),
+            Some(ErrorCode::ReserveInsolvent.into())
+        );
+    }
+
+    #[test]
+    fn emode_turns_off_only_for_assets_outside_the_category() {
+        let category = stablecoin_emode();
+        let listed = reserve(Pubkey::new_unique());
+        let outside = AssetInfo { emode_category: 2, ..reserve(Pubkey::new_unique()) };
+        let delisted = AssetInfo { delist_timestamp: 1, ..reserve(Pubkey::new_unique()) };
+
+        assert!(active_emode(1, &[&listed], Some(&category)).unwrap().is_some());
+        assert!(active_emode(1, &[&listed, &outside], Some(&category)).unwrap().is_none());
+        assert!(active_emode(1, &[&delisted], Some(&category)).unwrap().is_none());
+        assert!(active_emode(0, &[&listed], None).unwrap().is_none());
+    }
+
+    /// Run `check` against a throwaway account holding `data`
+    fn with_feed<T>(mut data: Vec<u8>, check: impl FnOnce(&AccountInfo) -> T) -> T {
+        let key = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let mut lamports = 0;
+        let feed = AccountInfo::new(&key, false, false, &mut lamports, &mut data, &owner, false, 0);
+        check(&feed)
+    }
+
+    fn write(data: &mut [u8], offset: usize, bytes: &[u8]) {
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn pyth_feed(price: i64, expo: i32, conf: u64, publish_time: i64, status: u32) -> Vec<u8> {
+        let mut data = vec![0; PYTH_PRICE_ACCOUNT_MIN_LEN];
+        write(&mut data, PYTH_MAGIC_OFFSET, &PYTH_MAGIC.to_le_bytes());
+        write(&mut data, PYTH_ACCOUNT_TYPE_OFFSET, &PYTH_ACCOUNT_TYPE_PRICE.to_le_bytes());
+        write(&mut data, PYTH_EXPONENT_OFFSET, &expo.to_le_bytes());
+        write(&mut data, PYTH_TIMESTAMP_OFFSET, &publish_time.to_le_bytes());
+        write(&mut data, PYTH_AGG_PRICE_OFFSET, &price.to_le_bytes());
+        write(&mut data, PYTH_AGG_CONF_OFFSET, &conf.to_le_bytes());
+        write(&mut data, PYTH_AGG_STATUS_OFFSET, &status.to_le_bytes());
+        write(&mut data, PYTH_AGG_PUB_SLOT_OFFSET, &77_u64.to_le_bytes());
+        data
+    }
+
+    /// An OCR2 transmissions account whose ring holds `(slot, timestamp, answer)` reports
+    fn chainlink_feed(decimals: u8, live_cursor: u32, transmissions: &[(u64, u32, i128)]) -> Vec<u8> {
+        let mut data = vec![0; CHAINLINK_TRANSMISSIONS_OFFSET + transmissions.len() * CHAINLINK_TRANSMISSION_SIZE];
+        data[CHAINLINK_DECIMALS_OFFSET] = decimals;
+        write(&mut data, CHAINLINK_LIVE_LENGTH_OFFSET, &(transmissions.len() as u32).to_le_bytes());
+        write(&mut data, CHAINLINK_LIVE_CURSOR_OFFSET, &live_cursor.to_le_bytes());
+        for (index, (slot, timestamp, answer)) in transmissions.iter().enumerate() {
+            let offset = CHAINLINK_TRANSMISSIONS_OFFSET + index * CHAINLINK_TRANSMISSION_SIZE;
+            write(&mut data, offset + CHAINLINK_TRANSMISSION_SLOT_OFFSET, &slot.to_le_bytes());
+            write(&mut data, offset + CHAINLINK_TRANSMISSION_TIMESTAMP_OFFSET, &timestamp.to_le_bytes());
+            write(&mut data, offset + CHAINLINK_TRANSMISSION_ANSWER_OFFSET, &answer.to_le_bytes());
+        }
+        data
+    }
+
+    fn switchboard_feed(result: (i128, u32), std_deviation: (i128, u32), num_success: u32, open_timestamp: i64) -> Vec<u8> {
+        let mut data = vec![0; SWITCHBOARD_AGGREGATOR_MIN_LEN];
+        write(&mut data, SWITCHBOARD_NUM_SUCCESS_OFFSET, &num_success.to_le_bytes());
+        write(&mut data, SWITCHBOARD_ROUND_OPEN_SLOT_OFFSET, &88_u64.to_le_bytes());
+        write(&mut data, SWITCHBOARD_ROUND_OPEN_TIMESTAMP_OFFSET, &open_timestamp.to_le_bytes());
+        write(&mut data, SWITCHBOARD_RESULT_OFFSET, &result.0.to_le_bytes());
+        write(&mut data, SWITCHBOARD_RESULT_OFFSET + 16, &result.1.to_le_bytes());
+        write(&mut data, SWITCHBOARD_STD_DEVIATION_OFFSET, &std_deviation.0.to_le_bytes());
+        write(&mut data, SWITCHBOARD_STD_DEVIATION_OFFSET + 16, &std_deviation.1.to_le_bytes());
+        data
+    }
+
+    fn invalid_price_data<T>(result: Result<T>) -> bool {
+        result.err() == Some(ErrorCode::InvalidPriceData.into())
+    }
+
+    #[test]
+    fn pyth_adapter_decodes_the_aggregate_price() {
+        // $150.12345 with five decimals
+        let price = with_feed(pyth_feed(15_012_345, -5, 30_000, 1_000, PYTH_STATUS_TRADING), |feed| {
+            PythAdapter::read_price(feed).unwrap()
+        });
+        assert_eq!(price.price, 15_012_345);
+        assert_eq!(price.expo, -5);
+        assert_eq!(price.conf, 30_000);
+        assert_eq!(price.publish_slot, 77);
+        assert_eq!(price.publish_time, 1_000);
+        assert_eq!(price.validate(1_000).unwrap(), 15_012_345_000);
+
+        // Halted or unknown status, the wrong account type or magic, and short buffers are rejected
+        let halted = pyth_feed(15_012_345, -5, 0, 1_000, 2);
+        assert!(with_feed(halted, |feed| invalid_price_data(PythAdapter::read_price(feed))));
+        let unknown = pyth_feed(15_012_345, -5, 0, 1_000, 0);
+        assert!(with_feed(unknown, |feed| invalid_price_data(PythAdapter::read_price(feed))));
+
+        let mut product = pyth_feed(15_012_345, -5, 0, 1_000, PYTH_STATUS_TRADING);
+        write(&mut product, PYTH_ACCOUNT_TYPE_OFFSET, &2_u32.to_le_bytes());
+        assert!(with_feed(product, |feed| invalid_price_data(PythAdapter::read_price(feed))));
+
+        let mut foreign = pyth_feed(15_012_345, -5, 0, 1_000, PYTH_STATUS_TRADING);
+        write(&mut foreign, PYTH_MAGIC_OFFSET, &0_u32.to_le_bytes());
+        assert!(with_feed(foreign, |feed| invalid_price_data(PythAdapter::read_price(feed))));
+
+        let mut short = pyth_feed(15_012_345, -5, 0, 1_000, PYTH_STATUS_TRADING);
+        short.truncate(PYTH_PRICE_ACCOUNT_MIN_LEN - 1);
+        assert!(with_feed(short, |feed| invalid_price_data(PythAdapter::read_price(feed))));
+    }
+
+    #[test]
+    fn chainlink_adapter_reads_the_latest_transmission() {
+        let ring = [(10, 1_000, 150), (11, 1_030, 151), (12, 1_060, 152)];
+
+        // The cursor points at the next slot to be written, so the latest report sits behind it
+        let price = with_feed(chainlink_feed(0, 2, &ring), |feed| ChainlinkAdapter::read_price(feed).unwrap());
+        assert_eq!((price.price, price.publish_slot, price.publish_time), (151, 11, 1_030));
+        assert_eq!(price.conf, 0);
+
+        // A cursor at zero wraps around to the end of the ring
+        let price = with_feed(chainlink_feed(0, 0, &ring), |feed| ChainlinkAdapter::read_price(feed).unwrap());
+        assert_eq!((price.price, price.publish_slot, price.publish_time), (152, 12, 1_060));
+        assert_eq!(price.validate(1_060).unwrap(), 15_200_000_000);
+
+        // 18 decimal answers are scaled down to 8 decimals
+        let wad = [(10, 1_000, 1_502_500_000_000_000_000)];
+        let price = with_feed(chainlink_feed(18, 1, &wad), |feed| ChainlinkAdapter::read_price(feed).unwrap());
+        assert_eq!(price.expo, -18);
+        assert_eq!(price.validate(1_000).unwrap(), 150_250_000);
+
+        // Empty rings and answers beyond i64 are rejected
+        assert!(with_feed(chainlink_feed(8, 0, &[]), |feed| invalid_price_data(ChainlinkAdapter::read_price(feed))));
+        let huge = [(10, 1_000, i64::MAX as i128 + 1)];
+        assert!(with_feed(chainlink_feed(8, 1, &huge), |feed| invalid_price_data(ChainlinkAdapter::read_price(feed))));
+    }
+
+    #[test]
+    fn switchboard_adapter_rescales_the_standard_deviation() {
+        // $150.0000 with a standard deviation of $0.25 at a coarser scale
+        let price = with_feed(switchboard_feed((1_500_000, 4), (25, 2), 3, 1_000), |feed| {
+            SwitchboardAdapter::read_price(feed).unwrap()
+        });
+        assert_eq!((price.price, price.expo, price.conf), (1_500_000, -4, 2_500));
+        assert_eq!((price.publish_slot, price.publish_time), (88, 1_000));
+        assert_eq!(price.validate(1_000).unwrap(), 15_000_000_000);
+
+        // The same deviation at a finer scale
+        let price = with_feed(switchboard_feed((1_500_000, 4), (250_000, 6), 3, 1_000), |feed| {
+            SwitchboardAdapter::read_price(feed).unwrap()
+        });
+        assert_eq!(price.conf, 2_500);
+
+        // A round without successful responses is rejected
+        let empty = switchboard_feed((1_500_000, 4), (25, 2), 0, 1_000);
+        assert!(with_feed(empty, |feed| invalid_price_data(SwitchboardAdapter::read_price(feed))));
+    }
+
+    #[test]
+    fn oracle_price_validation_rejects_stale_wide_and_non_positive_prices() {
+        let price = |price: i64, expo: i32, conf: u64, publish_time: i64| OraclePrice {
+            price,
+            expo,
+            conf,
+            publish_slot: 0,
+            publish_time,
+        };
+
+        // Staleness, including prices published in the future
+        assert_eq!(price(100, -8, 0, 1_000).validate(1_000 + MAX_PRICE_AGE_SECONDS).unwrap(), 100);
+        assert!(invalid_price_data(price(100, -8, 0, 1_000).validate(1_001 + MAX_PRICE_AGE_SECONDS)));
+        assert!(invalid_price_data(price(100, -8, 0, 1_001).validate(1_000)));
+
+        // Non-positive prices
+        assert!(invalid_price_data(price(0, -8, 0, 1_000).validate(1_000)));
+        assert!(invalid_price_data(price(-100, -8, 0, 1_000).validate(1_000)));
+
+        // Confidence up to 2% of the price
+        assert!(price(10_000, -8, 200, 1_000).validate(1_000).is_ok());
+        assert!(invalid_price_data(price(10_000, -8, 201, 1_000).validate(1_000)));
+
+        // Exponents above and below the target, and ones that scale out of range
+        assert_eq!(price(150, 0, 0, 1_000).validate(1_000).unwrap(), 15_000_000_000);
+        assert_eq!(price(15_000_000_000, -10, 0, 1_000).validate(1_000).unwrap(), 150_000_000);
+        assert!(invalid_price_data(price(99, -10, 0, 1_000).validate(1_000)));
+        assert!(invalid_price_data(price(150, 12, 0, 1_000).validate(1_000)));
+    }
+
+    fn empty_history() -> PriceHistory {
+        PriceHistory {
+            mint: Pubkey::new_unique(),
+            samples: [PriceSample::default(); PRICE_HISTORY_CAPACITY],
+            head: 0,
+            len: 0,
+            twap: 0,
+            ema: 0,
+            last_update_timestamp: 0,
+            bump: 0,
+        }
+    }
+
+    #[test]
+    fn twap_gives_the_newest_sample_no_weight() {
+        let mut history = empty_history();
+        history.record_sample(100, 1_000).unwrap();
+        assert_eq!((history.twap, history.ema), (100, 100));
+
+        // The newest price has not been the latest for any time yet
+        history.record_sample(200, 1_060).unwrap();
+        assert_eq!(history.twap, 100);
+
+        // 100 for 60s and 200 for 120s
+        history.record_sample(300, 1_180).unwrap();
+        assert_eq!(history.twap, 166);
+    }
+
+    #[test]
+    fn samples_closer_than_the_minimum_interval_are_rejected() {
+        let mut history = empty_history();
+        history.record_sample(100, 1_000).unwrap();
+        assert_eq!(
+            history.record_sample(500, 1_000 + MIN_PRICE_SAMPLE_INTERVAL_SECONDS - 1).err(),
+            Some(ErrorCode::RateLimited.into())
+        );
+        assert_eq!((history.len, history.head, history.last_update_timestamp), (1, 1, 1_000));
+
+        history.record_sample(500, 1_000 + MIN_PRICE_SAMPLE_INTERVAL_SECONDS).unwrap();
+        assert_eq!(history.len, 2);
+    }
+
+    #[test]
+    fn ring_buffer_wraps_and_drops_the_oldest_samples() {
+        let mut history = empty_history();
+        let extra = 3;
+        for i in 0..PRICE_HISTORY_CAPACITY + extra {
+            history.record_sample(100 + i as u64, i as i64 * 60).unwrap();
+        }
+        assert_eq!(history.len as usize, PRICE_HISTORY_CAPACITY);
+        assert_eq!(history.head as usize, extra);
+
+        // The head now points at the oldest kept sample, and every kept sample but the newest is averaged
+        assert_eq!(history.samples[extra].price, 100 + extra as u64);
+        let weighted: u64 = (extra..PRICE_HISTORY_CAPACITY + extra - 1).map(|i| 100 + i as u64).sum();
+        assert_eq!(history.twap, weighted / (PRICE_HISTORY_CAPACITY as u64 - 1));
+    }
+
+    #[test]
+    fn ema_moves_toward_new_prices_over_its_period() {
+        let mut history = empty_history();
+        history.record_sample(100, 0).unwrap();
+
+        // Half a period moves the EMA half way
+        history.record_sample(200, EMA_PERIOD_SECONDS / 2).unwrap();
+        assert_eq!(history.ema, 150);
+
+        // A gap longer than the period replaces it outright
+        history.record_sample(400, EMA_PERIOD_SECONDS * 3).unwrap();
+        assert_eq!(history.ema, 400);
+    }
+
+    #[test]
+    fn borrow_rate_follows_the_kinked_curve() {
+        // 2% base, 4% up to the 80% kink, then 100% more to full utilization
+        let model = reserve(Pubkey::new_unique()).interest_rate_model;
+        assert_eq!(model.borrow_rate(0).unwrap(), WAD / 100 * 2);
+        assert_eq!(model.borrow_rate(WAD / 100 * 40).unwrap(), WAD / 100 * 4);
+        assert_eq!(model.borrow_rate(WAD / 100 * 80).unwrap(), WAD / 100 * 6);
+        assert_eq!(model.borrow_rate(WAD / 100 * 90).unwrap(), WAD / 100 * 56);
+        assert_eq!(model.borrow_rate(WAD).unwrap(), WAD / 100 * 106);
+    }
+
+    #[test]
+    fn accrual_grows_the_index_and_splits_interest_with_the_reserve_factor() {
+        let mut reserve = reserve(Pubkey::new_unique());
+        reserve.total_deposits = 1_000_000_000;
+        reserve.total_borrows = 800_000_000;
+        reserve.total_scaled_borrows = 800_000_000;
+
+        // A year at the kink: 6% on 800, with 10% of it kept as protocol reserves
+        assert_eq!(reserve.utilization().unwrap(), WAD / 100 * 80);
+        reserve.accrue_interest(SECONDS_PER_YEAR as i64).unwrap();
+        assert_eq!(reserve.cumulative_borrow_index, RAY / 100 * 106);
+        assert_eq!(reserve.total_borrows, 848_000_000);
+        assert_eq!(reserve.protocol_reserves, 4_800_000);
+        assert_eq!(reserve.total_deposits, 1_043_200_000);
+        assert_eq!(reserve.last_accrual_timestamp, SECONDS_PER_YEAR as i64);
+
+        // Accruing again in the same second changes nothing
+        reserve.accrue_interest(SECONDS_PER_YEAR as i64).unwrap();
+        assert_eq!(reserve.total_borrows, 848_000_000);
+    }
+
+    #[test]
+    fn accrual_at_full_utilization_uses_the_top_of_the_curve() {
+        let mut reserve = reserve(Pubkey::new_unique());
+        reserve.total_deposits = 1_000_000_000;
+        reserve.total_borrows = 1_000_000_000;
+        reserve.total_scaled_borrows = 1_000_000_000;
+        assert_eq!(reserve.utilization().unwrap(), WAD);
+
+        // Half a year at 106%
+        reserve.accrue_interest(SECONDS_PER_YEAR as i64 / 2).unwrap();
+        assert_eq!(reserve.cumulative_borrow_index, RAY / 100 * 153);
+        assert_eq!(reserve.total_borrows, 1_530_000_000);
+        assert_eq!(reserve.protocol_reserves, 53_000_000);
+        assert_eq!(reserve.total_deposits, 1_477_000_000);
+
+        // Utilization is capped at 100% even when borrows outgrow deposits
+        reserve.total_deposits = 1_000_000_000;
+        assert_eq!(reserve.utilization().unwrap(), WAD);
+    }
+
+    #[test]
+    fn reserve_share_of_interest_rounds_down() {
+        let mut reserve = reserve(Pubkey::new_unique());
+        reserve.credit_interest(19).unwrap();
+        assert_eq!((reserve.protocol_reserves, reserve.total_deposits), (1, 18));
+    }
+
+    /// A lending pool instruction with asset_info in the second account slot, as in FlashLoan
+    fn flash_ix(asset_info: Pubkey, data: Vec<u8>) -> Instruction {
+        Instruction {
+            program_id: crate::ID,
+            accounts: vec![
+                AccountMeta::new_readonly(Pubkey::new_unique(), false),
+                AccountMeta::new(asset_info, false),
+            ],
+            data,
+        }
+    }
+
+    fn borrow_ix(asset_info: Pubkey, amount: u64) -> Instruction {
+        flash_ix(asset_info, instruction::FlashBorrow { amount }.data())
+    }
+
+    fn repay_ix(asset_info: Pubkey, amount: u64, borrow_instruction_index: u8) -> Instruction {
+        flash_ix(asset_info, instruction::FlashRepay { amount, borrow_instruction_index }.data())
+    }
+
+    fn other_program_ix() -> Instruction {
+        Instruction { program_id: Pubkey::new_unique(), accounts: vec![], data: vec![1, 2, 3] }
+    }
+
+    fn check_borrow_at(transaction: &[Instruction], index: usize, amount: u64, asset_info: Pubkey) -> Result<()> {
+        check_flash_borrow(TRANSACTION_LEVEL_STACK_HEIGHT, index, amount, asset_info, |i| transaction.get(i).cloned())
+    }
+
+    fn check_repay_at(transaction: &[Instruction], index: usize, amount: u64, borrow_index: u8, asset_info: Pubkey) -> Result<()> {
+        check_flash_repay(
+            TRANSACTION_LEVEL_STACK_HEIGHT,
+            index,
+            amount,
+            borrow_index,
+            asset_info,
+            |i| transaction.get(i).cloned(),
+        )
+    }
+
+    fn invalid_flash_loan(result: Result<()>) -> bool {
+        result.err() == Some(ErrorCode::InvalidFlashLoan.into())
+    }
+
+    #[test]
+    fn flash_borrow_and_repay_pair_up_around_other_instructions() {
+        let usdc = Pubkey::new_unique();
+        let transaction = [other_program_ix(), borrow_ix(usdc, 500), other_program_ix(), repay_ix(usdc, 500, 1)];
+        assert!(check_borrow_at(&transaction, 1, 500, usdc).is_ok());
+        assert!(check_repay_at(&transaction, 3, 500, 1, usdc).is_ok());
+    }
+
+    #[test]
+    fn flash_borrow_without_a_repay_is_rejected() {
+        let usdc = Pubkey::new_unique();
+        let transaction = [borrow_ix(usdc, 500), other_program_ix()];
+        assert_eq!(
+            check_borrow_at(&transaction, 0, 500, usdc).err(),
+            Some(ErrorCode::FlashLoanNotRepaid.into())
+        );
+    }
+
+    #[test]
+    fn flash_repay_must_match_the_borrowed_amount_and_reserve() {
+        let usdc = Pubkey::new_unique();
+        let weth = Pubkey::new_unique();
+
+        let short = [borrow_ix(usdc, 500), repay_ix(usdc, 499, 0)];
+        assert!(invalid_flash_loan(check_borrow_at(&short, 0, 500, usdc)));
+        assert!(invalid_flash_loan(check_repay_at(&short, 1, 499, 0, usdc)));
+
+        let other_reserve = [borrow_ix(usdc, 500), repay_ix(weth, 500, 0)];
+        assert!(invalid_flash_loan(check_borrow_at(&other_reserve, 0, 500, usdc)));
+        assert!(invalid_flash_loan(check_repay_at(&other_reserve, 1, 500, 0, weth)));
+
+        // The repay must point back at the borrow it settles
+        let wrong_index = [other_program_ix(), borrow_ix(usdc, 500), repay_ix(usdc, 500, 0)];
+        assert!(invalid_flash_loan(check_borrow_at(&wrong_index, 1, 500, usdc)));
+        assert!(invalid_flash_loan(check_repay_at(&wrong_index, 2, 500, 0, usdc)));
+        assert!(invalid_flash_loan(check_repay_at(&wrong_index, 2, 500, 2, usdc)));
+    }
+
+    #[test]
+    fn nested_borrows_and_duplicate_repays_are_rejected() {
+        let usdc = Pubkey::new_unique();
+        let nested = [borrow_ix(usdc, 500), borrow_ix(usdc, 500), repay_ix(usdc, 500, 1), repay_ix(usdc, 500, 0)];
+        assert!(invalid_flash_loan(check_borrow_at(&nested, 0, 500, usdc)));
+
+        // A second repay would take the same borrow off total_borrows twice
+        let duplicate = [borrow_ix(usdc, 500), repay_ix(usdc, 500, 0), repay_ix(usdc, 500, 0)];
+        assert!(invalid_flash_loan(check_repay_at(&duplicate, 1, 500, 0, usdc)));
+        assert!(invalid_flash_loan(check_repay_at(&duplicate, 2, 500, 0, usdc)));
+    }
+
+    #[test]
+    fn flash_loans_cannot_be_driven_through_cpi() {
+        let usdc = Pubkey::new_unique();
+        let transaction = [borrow_ix(usdc, 500), repay_ix(usdc, 500, 0)];
+        let load = |i: usize| transaction.get(i).cloned();
+        let cpi_height = TRANSACTION_LEVEL_STACK_HEIGHT + 1;
+        assert!(invalid_flash_loan(check_flash_borrow(cpi_height, 0, 500, usdc, load)));
+        assert!(invalid_flash_loan(check_flash_repay(cpi_height, 1, 500, 0, usdc, load)));
+    }
+
+    #[test]
+    fn deviating_sources_report_the_event_instead_of_a_price() {
+        let mut asset = reserve(Pubkey::new_unique());
+        asset.price_aggregation = PriceAggregation::Median;
+        asset.max_price_deviation_bps = 100;
+
+        // 1% apart is within the limit and prices at the midpoint
+        match aggregate_prices(&asset, WAD, WAD / 100 * 101) {
+            AssetPrice::Valid(price) => assert_eq!(price, WAD / 1_000 * 1_005),
+            AssetPrice::Deviated(_) => panic!("sources within the limit deviated"),
+        }
+
+        // 2% apart is reported with the observed spread
+        match aggregate_prices(&asset, WAD, WAD / 100 * 102) {
+            AssetPrice::Valid(_) => panic!("sources outside the limit were priced"),
+            AssetPrice::Deviated(event) => {
+                assert_eq!(event.mint, asset.mint);
+                assert_eq!(event.deviation_bps, 200);
+                assert_eq!(event.max_deviation_bps, 100);
+            }
+        }
+    }
+}
@@ -6,13 +6,43 @@ const SOLANA_RPC_URL = process.env.SOLANA_RPC_URL || 'https://api.devnet.solana.
 const LENDING_POOL_PROGRAM_ID = new PublicKey('ss9Hb9bSa6jW2w3UUNBN2aGviAUVMmnwVZ71HZw6xBL');
 const BONK_MINT = new PublicKey('DezXAZ8z7PnrnRJjz3wXBoRgixCa6xjnB7YaB1pPB263');
 
-// Bonk configuration
+// Bonk configuration - listed as a volatile-tier asset
+const PYTH_PROGRAM_ID = new PublicKey('gSbePebfvPy7tRqimPoVecS2UsBvYv46ynrzWocc92s'); // Pyth devnet program
 const BONK_CONFIG = {
-  priceFeed: new PublicKey('11111111111111111111111111111111'), // Placeholder - replace with actual Chainlink price feed
-  ltv: new BN(600_000_000_000_000_000), // 0.60 (60%)
-  liquidationThreshold: new BN(800_000_000_000_000_000), // 0.80 (80%)
+  priceFeed: new PublicKey('11111111111111111111111111111111'), // Placeholder - replace with actual Pyth BONK/USD price account
+  oracleSource: { pyth: {} },
+  oracleProgram: PYTH_PROGRAM_ID,
+  secondaryPriceFeed: null,
+  secondaryOracleSource: { pyth: {} },
+  secondaryOracleProgram: PYTH_PROGRAM_ID,
+  priceAggregation: { primary: {} },
+  maxPriceDeviationBps: new BN(0),
+  useTwap: true,
+  ltv: new BN('600000000000000000'), // 0.60 (60%)
+  liquidationThreshold: new BN('800000000000000000'), // 0.80 (80%)
+  liquidationBonus: new BN('100000000000000000'), // 10% - larger incentive for a volatile asset
+  protocolLiquidationFee: new BN('200000000000000000'), // 20% of the bonus goes to reserves
+  liquidationAuctionSlots: new BN(150), // Bonus ramps up over ~1 minute
   canBeCollateral: true,
   canBeBorrowed: true,
+  interestRateModel: {
+    baseRate: new BN('20000000000000000'), // 2%
+    slope1: new BN('100000000000000000'), // 10%
+    optimalUtilization: new BN('450000000000000000'), // 45%
+    slope2: new BN('3000000000000000000'), // 300%
+  },
+  reserveFactor: new BN('200000000000000000'), // 20%
+  flashLoanFee: new BN('900000000000000'), // 0.09%
+  supplyCap: new BN('1000000000000000'), // 10B BONK (5 decimals)
+  borrowCap: new BN('200000000000000'), // 2B BONK (5 decimals)
+  riskTier: { volatile: {} },
+  minDepositAmount: new BN(1000),
+  minBorrowAmount: new BN(1000),
+  isolated: false,
+  debtCeilingUsd: new BN(0),
+  borrowableInIsolation: false,
+  emodeCategory: 0,
+  closeFactor: new BN('500000000000000000'), // 50% of a borrow per liquidation
 };
 
 export async function addBonkAsset() {
@@ -39,6 +69,18 @@ export async function addBonkAsset() {
       LENDING_POOL_PROGRAM_ID
     );
     
+    // Generate receipt share mint PDA for Bonk
+    const [shareMintPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from('shares'), BONK_MINT.toBuffer()],
+      LENDING_POOL_PROGRAM_ID
+    );
+    
+    // Generate collateral escrow PDA holding Bonk receipt shares
+    const [collateralEscrowPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from('collateral'), BONK_MINT.toBuffer()],
+      LENDING_POOL_PROGRAM_ID
+    );
+    
     console.log('🚀 Adding Bonk as supported asset...');
     console.log('Pool PDA:', poolPda.toString());
     console.log('Asset Info PDA:', assetInfoPda.toString());
@@ -46,18 +88,15 @@ export async function addBonkAsset() {
     
     // Add Bonk as supported asset
     const tx = await program.methods
-      .addSupportedAsset({
-        priceFeed: BONK_CONFIG.priceFeed,
-        ltv: BONK_CONFIG.ltv,
-        liquidationThreshold: BONK_CONFIG.liquidationThreshold,
-        canBeCollateral: BONK_CONFIG.canBeCollateral,
-        canBeBorrowed: BONK_CONFIG.canBeBorrowed,
-      })
+      .addSupportedAsset(BONK_CONFIG)
       .accounts({
         pool: poolPda,
         assetInfo: assetInfoPda,
         mint: BONK_MINT,
+        shareMint: shareMintPda,
+        collateralEscrow: collateralEscrowPda,
         admin: wallet.publicKey,
+        tokenProgram: new PublicKey('TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA'),
         systemProgram: new PublicKey('11111111111111111111111111111111'),
       })
       .rpc();
@@ -0,0 +1,384 @@
+import * as anchor from "@coral-xyz/anchor";
+import { Program } from "@coral-xyz/anchor";
+import { BankrunProvider, startAnchor } from "anchor-bankrun";
+import { Clock, ProgramTestContext } from "solana-bankrun";
+import {
+  Keypair,
+  LAMPORTS_PER_SOL,
+  PublicKey,
+  SystemProgram,
+  SYSVAR_INSTRUCTIONS_PUBKEY,
+  Transaction,
+  TransactionInstruction,
+} from "@solana/web3.js";
+import {
+  ACCOUNT_SIZE,
+  AccountLayout,
+  MINT_SIZE,
+  MintLayout,
+  TOKEN_PROGRAM_ID,
+} from "@solana/spl-token";
+import { expect } from "chai";
+import BN from "bn.js";
+import { AssetInfo, Obligation } from "../types/lending_pool";
+import { assetConfig, RAY } from "./helpers";
+import IDL from "../target/idl/lending_pool.json";
+
+// Borrowing needs a fresh oracle price and a refresh in the same slot, and the rate limit
+// spans 15 minutes, so these flows run in bankrun where the clock and price feed are ours
+describe("Borrowing and flash loans", () => {
+  const USDC_DECIMALS = 6;
+  const usdc = (amount: number) => new BN(amount).mul(new BN(10).pow(new BN(USDC_DECIMALS)));
+
+  const programId = new PublicKey(IDL.address);
+  const admin = Keypair.generate();
+  const user = Keypair.generate();
+  const usdcMint = Keypair.generate().publicKey;
+  const userUsdcAccount = Keypair.generate().publicKey;
+  const poolUsdcAccount = Keypair.generate().publicKey;
+  const userShareAccount = Keypair.generate().publicKey;
+  const oracleProgram = Keypair.generate().publicKey;
+  const priceFeed = Keypair.generate().publicKey;
+
+  const [poolAccount] = PublicKey.findProgramAddressSync([Buffer.from("pool")], programId);
+  const [assetInfo] = PublicKey.findProgramAddressSync([Buffer.from("asset"), usdcMint.toBuffer()], programId);
+  const [shareMint] = PublicKey.findProgramAddressSync([Buffer.from("shares"), usdcMint.toBuffer()], programId);
+  const [collateralEscrow] = PublicKey.findProgramAddressSync(
+    [Buffer.from("collateral"), usdcMint.toBuffer()],
+    programId
+  );
+  const [obligation] = PublicKey.findProgramAddressSync(
+    [Buffer.from("obligation"), user.publicKey.toBuffer()],
+    programId
+  );
+
+  let context: ProgramTestContext;
+  let provider: BankrunProvider;
+  let program: Program;
+
+  const systemAccount = () => ({
+    lamports: 100 * LAMPORTS_PER_SOL,
+    data: Buffer.alloc(0),
+    owner: SystemProgram.programId,
+    executable: false,
+  });
+
+  const mintAccount = (authority: PublicKey) => {
+    const data = Buffer.alloc(MINT_SIZE);
+    MintLayout.encode(
+      {
+        mintAuthorityOption: 1,
+        mintAuthority: authority,
+        supply: BigInt(0),
+        decimals: USDC_DECIMALS,
+        isInitialized: true,
+        freezeAuthorityOption: 0,
+        freezeAuthority: PublicKey.default,
+      },
+      data
+    );
+    return { lamports: LAMPORTS_PER_SOL, data, owner: TOKEN_PROGRAM_ID, executable: false };
+  };
+
+  const tokenAccount = (mint: PublicKey, owner: PublicKey, amount: BN) => {
+    const data = Buffer.alloc(ACCOUNT_SIZE);
+    AccountLayout.encode(
+      {
+        mint,
+        owner,
+        amount: BigInt(amount.toString()),
+        delegateOption: 0,
+        delegate: PublicKey.default,
+        state: 1, // Initialized
+        isNativeOption: 0,
+        isNative: BigInt(0),
+        delegatedAmount: BigInt(0),
+        closeAuthorityOption: 0,
+        closeAuthority: PublicKey.default,
+      },
+      data
+    );
+    return { lamports: LAMPORTS_PER_SOL, data, owner: TOKEN_PROGRAM_ID, executable: false };
+  };
+
+  const tokenBalance = async (address: PublicKey) => {
+    const account = await context.banksClient.getAccount(address);
+    return new BN(AccountLayout.decode(Buffer.from(account!.data)).amount.toString());
+  };
+
+  // Pyth price account layout: magic, account type, exponent, timestamp and the aggregate price
+  const setPrice = async (price: BN) => {
+    const clock = await context.banksClient.getClock();
+    const data = Buffer.alloc(240);
+    data.writeUInt32LE(0xa1b2c3d4, 0);
+    data.writeUInt32LE(3, 8);
+    data.writeInt32LE(-8, 20);
+    data.writeBigInt64LE(clock.unixTimestamp, 96);
+    data.writeBigInt64LE(BigInt(price.toString()), 208);
+    data.writeBigUInt64LE(BigInt(0), 216);
+    data.writeUInt32LE(1, 224); // Trading
+    data.writeBigUInt64LE(clock.slot, 232);
+    context.setAccount(priceFeed, { lamports: LAMPORTS_PER_SOL, data, owner: oracleProgram, executable: false });
+  };
+
+  // Move past the 15 minute rate limit and republish the price at the new time
+  const advanceTime = async (seconds: number) => {
+    const clock = await context.banksClient.getClock();
+    context.setClock(
+      new Clock(
+        clock.slot + BigInt(1),
+        clock.epochStartTimestamp,
+        clock.epoch,
+        clock.leaderScheduleEpoch,
+        clock.unixTimestamp + BigInt(seconds)
+      )
+    );
+    await setPrice(new BN(100_000_000));
+  };
+
+  const send = async (instructions: TransactionInstruction[], signers: Keypair[] = [user]) => {
+    await provider.sendAndConfirm(new Transaction().add(...instructions), signers);
+  };
+
+  const expectProgramError = async (instructions: TransactionInstruction[], name: string) => {
+    const error = IDL.errors.find((candidate) => candidate.name === name)!;
+    try {
+      await send(instructions);
+    } catch (err) {
+      expect(String(err)).to.include(`0x${error.code.toString(16)}`);
+      return;
+    }
+    expect.fail(`Expected ${name}`);
+  };
+
+  const refreshIx = () =>
+    program.methods
+      .refreshObligation()
+      .accounts({ obligation, emodeCategory: null })
+      .remainingAccounts([
+        { pubkey: assetInfo, isSigner: false, isWritable: false },
+        { pubkey: priceFeed, isSigner: false, isWritable: false },
+      ])
+      .instruction();
+
+  const borrowIx = (amount: BN) =>
+    program.methods
+      .borrowCrossChain(amount, new BN("12532609583862916517"), Array.from(user.publicKey.toBuffer()))
+      .accounts({
+        pool: poolAccount,
+        assetInfo,
+        obligation,
+        mint: usdcMint,
+        priceFeed,
+        secondaryPriceFeed: null,
+        priceHistory: null,
+        isolatedAssetInfo: null,
+        ccipProgram: Keypair.generate().publicKey,
+        user: user.publicKey,
+      })
+      .instruction();
+
+  const unlockIx = (shares: BN) =>
+    program.methods
+      .unlockCollateral(shares)
+      .accounts({
+        pool: poolAccount,
+        assetInfo,
+        obligation,
+        mint: usdcMint,
+        emodeCategory: null,
+        shareMint,
+        userShareAccount,
+        collateralEscrow,
+        user: user.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .instruction();
+
+  const flashAccounts = () => ({
+    pool: poolAccount,
+    assetInfo,
+    mint: usdcMint,
+    poolTokenAccount: poolUsdcAccount,
+    userTokenAccount: userUsdcAccount,
+    user: user.publicKey,
+    instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+    tokenProgram: TOKEN_PROGRAM_ID,
+  });
+
+  const flashBorrowIx = (amount: BN) =>
+    program.methods.flashBorrow(amount).accounts(flashAccounts()).instruction();
+
+  const flashRepayIx = (amount: BN, borrowInstructionIndex: number) =>
+    program.methods.flashRepay(amount, borrowInstructionIndex).accounts(flashAccounts()).instruction();
+
+  before(async () => {
+    context = await startAnchor(".", [], [
+      { address: admin.publicKey, info: systemAccount() },
+      { address: user.publicKey, info: systemAccount() },
+      { address: usdcMint, info: mintAccount(admin.publicKey) },
+      { address: userUsdcAccount, info: tokenAccount(usdcMint, user.publicKey, usdc(1_000_000)) },
+      { address: poolUsdcAccount, info: tokenAccount(usdcMint, poolAccount, new BN(0)) },
+    ]);
+    provider = new BankrunProvider(context);
+    program = new Program(IDL as anchor.Idl, provider);
+    await setPrice(new BN(100_000_000)); // $1.00
+
+    await program.methods
+      .initialize(admin.publicKey, Keypair.generate().publicKey, admin.publicKey)
+      .accounts({ pool: poolAccount, admin: admin.publicKey, systemProgram: SystemProgram.programId })
+      .signers([admin])
+      .rpc();
+
+    await program.methods
+      .addSupportedAsset(assetConfig(priceFeed, oracleProgram))
+      .accounts({
+        pool: poolAccount,
+        assetInfo,
+        mint: usdcMint,
+        shareMint,
+        collateralEscrow,
+        admin: admin.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+    context.setAccount(userShareAccount, tokenAccount(shareMint, user.publicKey, new BN(0)));
+
+    // Supply 10,000 USDC and lock the shares as collateral
+    await send([
+      await program.methods
+        .deposit(usdc(10_000))
+        .accounts({
+          pool: poolAccount,
+          assetInfo,
+          mint: usdcMint,
+          shareMint,
+          userShareAccount,
+          userTokenAccount: userUsdcAccount,
+          poolTokenAccount: poolUsdcAccount,
+          user: user.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .instruction(),
+      await program.methods
+        .lockCollateral(usdc(10_000))
+        .accounts({
+          pool: poolAccount,
+          assetInfo,
+          obligation,
+          mint: usdcMint,
+          shareMint,
+          userShareAccount,
+          collateralEscrow,
+          user: user.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .instruction(),
+    ]);
+  });
+
+  it("Borrows cross-chain right after locking collateral, refreshed in the same transaction", async () => {
+    const borrowAmount = usdc(5_000);
+    await send([await refreshIx(), await borrowIx(borrowAmount)]);
+
+    // Debt is stored scaled by the borrow index
+    const obligationState = (await program.account.obligation.fetch(obligation)) as unknown as Obligation;
+    const assetState = (await program.account.assetInfo.fetch(assetInfo)) as unknown as AssetInfo;
+    const debt = obligationState.borrows[0].scaledBorrowBalance.mul(assetState.cumulativeBorrowIndex).div(RAY);
+    expect(debt.gte(borrowAmount)).to.be.true;
+    expect(assetState.totalBorrows.toString()).to.equal(borrowAmount.toString());
+  });
+
+  it("Rejects a borrow without a refresh in the same slot", async () => {
+    await advanceTime(901);
+    await expectProgramError([await borrowIx(usdc(100))], "obligationStale");
+  });
+
+  it("Rate limits borrows for 15 minutes", async () => {
+    await send([await refreshIx(), await borrowIx(usdc(200))]);
+    await expectProgramError([await refreshIx(), await borrowIx(usdc(300))], "rateLimited");
+
+    await advanceTime(901);
+    await send([await refreshIx(), await borrowIx(usdc(400))]);
+  });
+
+  it("Repays without waiting out the rate limit", async () => {
+    const repayAmount = usdc(2_000);
+    const poolBalance = await tokenBalance(poolUsdcAccount);
+
+    await send([
+      await program.methods
+        .repay(repayAmount)
+        .accounts({
+          pool: poolAccount,
+          assetInfo,
+          obligation,
+          mint: usdcMint,
+          isolatedAssetInfo: null,
+          userTokenAccount: userUsdcAccount,
+          poolTokenAccount: poolUsdcAccount,
+          user: user.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .instruction(),
+    ]);
+
+    expect((await tokenBalance(poolUsdcAccount)).sub(poolBalance).toString()).to.equal(repayAmount.toString());
+  });
+
+  it("Refuses to unlock collateral that backs the debt", async () => {
+    await advanceTime(901);
+    await expectProgramError([await refreshIx(), await unlockIx(usdc(10_000))], "healthFactorTooLow");
+
+    // Shares beyond what the debt needs can be unlocked and transferred freely
+    await send([await refreshIx(), await unlockIx(usdc(1_000))]);
+    expect((await tokenBalance(userShareAccount)).toString()).to.equal(usdc(1_000).toString());
+  });
+
+  it("Flash borrows and repays with the fee in one transaction", async () => {
+    const amount = usdc(1_000);
+    const fee = usdc(1_000).muln(9).divn(10_000); // 0.09%
+    const poolBalance = await tokenBalance(poolUsdcAccount);
+
+    await send([await flashBorrowIx(amount), await flashRepayIx(amount, 0)]);
+
+    expect((await tokenBalance(poolUsdcAccount)).sub(poolBalance).toString()).to.equal(fee.toString());
+  });
+
+  it("Rejects a flash borrow without a repay", async () => {
+    await expectProgramError([await flashBorrowIx(usdc(1_000))], "flashLoanNotRepaid");
+  });
+
+  it("Rejects a flash repay of a different amount", async () => {
+    await expectProgramError(
+      [await flashBorrowIx(usdc(1_000)), await flashRepayIx(usdc(999), 0)],
+      "invalidFlashLoan"
+    );
+  });
+
+  it("Rejects nested flash borrows", async () => {
+    await expectProgramError(
+      [
+        await flashBorrowIx(usdc(1_000)),
+        await flashBorrowIx(usdc(500)),
+        await flashRepayIx(usdc(500), 1),
+        await flashRepayIx(usdc(1_000), 0),
+      ],
+      "invalidFlashLoan"
+    );
+  });
+
+  it("Rejects a second repay of the same flash borrow", async () => {
+    await expectProgramError(
+      [
+        await flashBorrowIx(usdc(2_000)),
+        await flashRepayIx(usdc(2_000), 0),
+        await flashRepayIx(usdc(2_000), 0),
+      ],
+      "invalidFlashLoan"
+    );
+  });
+});
@@ -0,0 +1,42 @@
+import { PublicKey } from "@solana/web3.js";
+import BN from "bn.js";
+
+export const RAY = new BN("1000000000000000000000000000"); // Scale of the borrow index
+
+// Full AssetConfig for a standard-tier reserve priced by a single feed
+export const assetConfig = (priceFeed: PublicKey, oracleProgram: PublicKey) => ({
+  priceFeed,
+  oracleSource: { pyth: {} },
+  oracleProgram,
+  secondaryPriceFeed: null,
+  secondaryOracleSource: { pyth: {} },
+  secondaryOracleProgram: oracleProgram,
+  priceAggregation: { primary: {} },
+  maxPriceDeviationBps: new BN(0),
+  useTwap: false,
+  ltv: new BN("750000000000000000"), // 75%
+  liquidationThreshold: new BN("850000000000000000"), // 85%
+  liquidationBonus: new BN("50000000000000000"), // 5%
+  protocolLiquidationFee: new BN("100000000000000000"), // 10% of the bonus
+  liquidationAuctionSlots: new BN(0), // Fixed bonus
+  canBeCollateral: true,
+  canBeBorrowed: true,
+  interestRateModel: {
+    baseRate: new BN("0"),
+    slope1: new BN("40000000000000000"), // 4%
+    optimalUtilization: new BN("800000000000000000"), // 80%
+    slope2: new BN("750000000000000000"), // 75%
+  },
+  reserveFactor: new BN("100000000000000000"), // 10%
+  flashLoanFee: new BN("900000000000000"), // 0.09%
+  supplyCap: new BN(0), // No cap
+  borrowCap: new BN(0), // No cap
+  riskTier: { standard: {} },
+  minDepositAmount: new BN(1),
+  minBorrowAmount: new BN(1),
+  isolated: false,
+  debtCeilingUsd: new BN(0),
+  borrowableInIsolation: false,
+  emodeCategory: 0,
+  closeFactor: new BN("500000000000000000"), // 50% of a borrow per liquidation
+});
@@ -1,6 +1,6 @@
 import * as anchor from "@coral-xyz/anchor";
 import { Program } from "@coral-xyz/anchor";
-import { LendingPool, Pool, AssetInfo, UserPosition } from "../types/lending_pool";
+import { LendingPool, Pool, AssetInfo, Obligation } from "../types/lending_pool";
 import {
   PublicKey,
   Keypair,
@@ -19,6 +19,7 @@ import {
 } from "@solana/spl-token";
 import { expect } from "chai";
 import BN from "bn.js";
+import { assetConfig } from "./helpers";
 
 describe("Cross-Chain Lending Pool", () => {
   // Configure the client to use the local cluster
@@ -42,16 +43,20 @@ describe("Cross-Chain Lending Pool", () => {
   let poolAccount: PublicKey;
   let usdcAssetInfo: PublicKey;
   let wethAssetInfo: PublicKey;
+  let usdcShareMint: PublicKey;
+  let wethShareMint: PublicKey;
+  let usdcCollateralEscrow: PublicKey;
+  let wethCollateralEscrow: PublicKey;
 
   // User token accounts
   let userUsdcAccount: PublicKey;
   let userWethAccount: PublicKey;
   let poolUsdcAccount: PublicKey;
   let poolWethAccount: PublicKey;
+  let userUsdcShareAccount: PublicKey;
 
-  // User position accounts
-  let userUsdcPosition: PublicKey;
-  let userWethPosition: PublicKey;
+  // User obligation, holding deposits and borrows across every reserve
+  let userObligation: PublicKey;
 
   // Constants
   const LAMPORTS_PER_SOL = 1_000_000_000;
@@ -142,13 +147,28 @@ describe("Cross-Chain Lending Pool", () => {
       program.programId
     );
 
-    [userUsdcPosition] = PublicKey.findProgramAddressSync(
-      [Buffer.from("position"), user.publicKey.toBuffer(), usdcMint.toBuffer()],
+    [usdcShareMint] = PublicKey.findProgramAddressSync(
+      [Buffer.from("shares"), usdcMint.toBuffer()],
       program.programId
     );
 
-    [userWethPosition] = PublicKey.findProgramAddressSync(
-      [Buffer.from("position"), user.publicKey.toBuffer(), wethMint.toBuffer()],
+    [wethShareMint] = PublicKey.findProgramAddressSync(
+      [Buffer.from("shares"), wethMint.toBuffer()],
+      program.programId
+    );
+
+    [usdcCollateralEscrow] = PublicKey.findProgramAddressSync(
+      [Buffer.from("collateral"), usdcMint.toBuffer()],
+      program.programId
+    );
+
+    [wethCollateralEscrow] = PublicKey.findProgramAddressSync(
+      [Buffer.from("collateral"), wethMint.toBuffer()],
+      program.programId
+    );
+
+    [userObligation] = PublicKey.findProgramAddressSync(
+      [Buffer.from("obligation"), user.publicKey.toBuffer()],
       program.programId
     );
 
@@ -172,7 +192,7 @@ describe("Cross-Chain Lending Pool", () => {
     const ccipProgram = Keypair.generate().publicKey; // Mock CCIP program
 
     const tx = await program.methods
-      .initialize(admin.publicKey, ccipProgram)
+      .initialize(admin.publicKey, ccipProgram, admin.publicKey)
       .accounts({
         pool: poolAccount,
         admin: admin.publicKey,
@@ -193,15 +213,10 @@ describe("Cross-Chain Lending Pool", () => {
 
   it("Add supported assets", async () => {
     const mockPriceFeed = Keypair.generate().publicKey;
+    const mockOracleProgram = Keypair.generate().publicKey;
 
     // Add USDC
-    const usdcConfig = {
-      priceFeed: mockPriceFeed,
-      ltv: new BN("750000000000000000"), // 75%
-      liquidationThreshold: new BN("850000000000000000"), // 85%
-      canBeCollateral: true,
-      canBeBorrowed: true,
-    };
+    const usdcConfig = assetConfig(mockPriceFeed, mockOracleProgram);
 
     await program.methods
       .addSupportedAsset(usdcConfig)
@@ -209,20 +224,17 @@ describe("Cross-Chain Lending Pool", () => {
         pool: poolAccount,
         assetInfo: usdcAssetInfo,
         mint: usdcMint,
+        shareMint: usdcShareMint,
+        collateralEscrow: usdcCollateralEscrow,
         admin: admin.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
         systemProgram: SystemProgram.programId,
       })
       .signers([admin])
       .rpc();
 
     // Add WETH
-    const wethConfig = {
-      priceFeed: mockPriceFeed,
-      ltv: new BN("750000000000000000"), // 75%
-      liquidationThreshold: new BN("850000000000000000"), // 85%
-      canBeCollateral: true,
-      canBeBorrowed: true,
-    };
+    const wethConfig = assetConfig(mockPriceFeed, mockOracleProgram);
 
     await program.methods
       .addSupportedAsset(wethConfig)
@@ -230,7 +242,10 @@ describe("Cross-Chain Lending Pool", () => {
         pool: poolAccount,
         assetInfo: wethAssetInfo,
         mint: wethMint,
+        shareMint: wethShareMint,
+        collateralEscrow: wethCollateralEscrow,
         admin: admin.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
         systemProgram: SystemProgram.programId,
       })
       .signers([admin])
@@ -242,110 +257,119 @@ describe("Cross-Chain Lending Pool", () => {
     expect(usdcInfo.isActive).to.be.true;
     expect(usdcInfo.canBeCollateral).to.be.true;
     expect(usdcInfo.canBeBorrowed).to.be.true;
+    expect(usdcInfo.shareMint.toString()).to.equal(usdcShareMint.toString());
+    expect(usdcInfo.liquidationBonus.toString()).to.equal(usdcConfig.liquidationBonus.toString());
+    expect(usdcInfo.closeFactor.toString()).to.equal(usdcConfig.closeFactor.toString());
 
     const wethInfo = await program.account.assetInfo.fetch(wethAssetInfo) as AssetInfo;
     expect(wethInfo.mint.toString()).to.equal(wethMint.toString());
     expect(wethInfo.isActive).to.be.true;
   });
 
-  it("Deposit collateral", async () => {
+  it("Deposit liquidity", async () => {
     const depositAmount = new BN(1000 * Math.pow(10, USDC_DECIMALS)); // 1000 USDC
 
+    // Receipt shares are minted to the user's own share token account
+    userUsdcShareAccount = await createAssociatedTokenAccount(
+      provider.connection,
+      payer.payer,
+      usdcShareMint,
+      user.publicKey
+    );
+
     await program.methods
       .deposit(depositAmount)
       .accounts({
         pool: poolAccount,
         assetInfo: usdcAssetInfo,
-        userPosition: userUsdcPosition,
         mint: usdcMint,
+        shareMint: usdcShareMint,
+        userShareAccount: userUsdcShareAccount,
         userTokenAccount: userUsdcAccount,
         poolTokenAccount: poolUsdcAccount,
         user: user.publicKey,
         tokenProgram: TOKEN_PROGRAM_ID,
-        systemProgram: SystemProgram.programId,
       })
       .signers([user])
       .rpc();
 
-        // Verify user position
-    const position = await program.account.userPosition.fetch(userUsdcPosition) as UserPosition;
-    expect(position.user.toString()).to.equal(user.publicKey.toString());
-    expect(position.collateralBalance.toString()).to.equal(depositAmount.toString());
-
-    // Verify asset info updated
+    // Verify asset info updated, the first deposit mints shares one-to-one
     const assetInfo = await program.account.assetInfo.fetch(usdcAssetInfo) as AssetInfo;
     expect(assetInfo.totalDeposits.toString()).to.equal(depositAmount.toString());
+    expect(assetInfo.totalShares.toString()).to.equal(depositAmount.toString());
+
+    // Verify the user holds the transferable receipt shares
+    const shareAccountInfo = await getAccount(provider.connection, userUsdcShareAccount);
+    expect(shareAccountInfo.amount.toString()).to.equal(depositAmount.toString());
 
     // Verify token transfer
     const poolTokenAccountInfo = await getAccount(provider.connection, poolUsdcAccount);
     expect(poolTokenAccountInfo.amount.toString()).to.equal(depositAmount.toString());
   });
 
-  it("Cross-chain borrow", async () => {
-    const borrowAmount = new BN(500 * Math.pow(10, WETH_DECIMALS)); // 500 WETH
-    const destChain = new BN(12532609583862916517); // Mumbai chain selector
-    const receiver = Array.from(user.publicKey.toBuffer()); // Convert to [u8; 32]
-
-    const mockPriceFeed = Keypair.generate().publicKey;
-    const mockCcipProgram = Keypair.generate().publicKey;
+  it("Lock and unlock collateral", async () => {
+    const lockedShares = new BN(700 * Math.pow(10, USDC_DECIMALS));
+    const unlockedShares = new BN(100 * Math.pow(10, USDC_DECIMALS));
 
     await program.methods
-      .borrowCrossChain(borrowAmount, destChain, receiver)
+      .lockCollateral(lockedShares)
       .accounts({
         pool: poolAccount,
-        assetInfo: wethAssetInfo,
-        userPosition: userWethPosition,
-        mint: wethMint,
-        priceFeed: mockPriceFeed,
-        ccipProgram: mockCcipProgram,
+        assetInfo: usdcAssetInfo,
+        obligation: userObligation,
+        mint: usdcMint,
+        shareMint: usdcShareMint,
+        userShareAccount: userUsdcShareAccount,
+        collateralEscrow: usdcCollateralEscrow,
         user: user.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
       })
       .signers([user])
       .rpc();
 
-        // Verify user position
-    const position = await program.account.userPosition.fetch(userWethPosition) as UserPosition;
-    expect(position.borrowBalance.toString()).to.equal(borrowAmount.toString());
-
-    // Verify asset info updated
-    const assetInfo = await program.account.assetInfo.fetch(wethAssetInfo) as AssetInfo;
-    expect(assetInfo.totalBorrows.toString()).to.equal(borrowAmount.toString());
-  });
-
-  it("Repay borrowed amount", async () => {
-    const repayAmount = new BN(250 * Math.pow(10, WETH_DECIMALS)); // 250 WETH
-
+    // Without debt the shares can be unlocked again straight away
     await program.methods
-      .repay(repayAmount)
+      .unlockCollateral(unlockedShares)
       .accounts({
         pool: poolAccount,
-        assetInfo: wethAssetInfo,
-        userPosition: userWethPosition,
-        mint: wethMint,
-        userTokenAccount: userWethAccount,
-        poolTokenAccount: poolWethAccount,
+        assetInfo: usdcAssetInfo,
+        obligation: userObligation,
+        mint: usdcMint,
+        emodeCategory: null,
+        shareMint: usdcShareMint,
+        userShareAccount: userUsdcShareAccount,
+        collateralEscrow: usdcCollateralEscrow,
         user: user.publicKey,
         tokenProgram: TOKEN_PROGRAM_ID,
       })
       .signers([user])
       .rpc();
 
-    // Verify user position updated
-    const position = await program.account.userPosition.fetch(userWethPosition) as UserPosition;
-    const expectedBalance = new BN(250 * Math.pow(10, WETH_DECIMALS)); // 500 - 250 = 250
-    expect(position.borrowBalance.toString()).to.equal(expectedBalance.toString());
+    // Verify obligation, only the shares still in escrow back it
+    const obligation = await program.account.obligation.fetch(userObligation) as Obligation;
+    const expectedLocked = lockedShares.sub(unlockedShares);
+    expect(obligation.owner.toString()).to.equal(user.publicKey.toString());
+    expect(obligation.deposits[0].mint.toString()).to.equal(usdcMint.toString());
+    expect(obligation.deposits[0].shares.toString()).to.equal(expectedLocked.toString());
+
+    const escrowAccountInfo = await getAccount(provider.connection, usdcCollateralEscrow);
+    expect(escrowAccountInfo.amount.toString()).to.equal(expectedLocked.toString());
+    const shareAccountInfo = await getAccount(provider.connection, userUsdcShareAccount);
+    expect(shareAccountInfo.amount.toString()).to.equal(new BN(400 * Math.pow(10, USDC_DECIMALS)).toString());
   });
 
-  it("Withdraw collateral", async () => {
-    const withdrawAmount = new BN(500 * Math.pow(10, USDC_DECIMALS)); // 500 USDC
+  it("Withdraw liquidity", async () => {
+    const withdrawAmount = new BN(200 * Math.pow(10, USDC_DECIMALS)); // 200 USDC
 
     await program.methods
       .withdraw(withdrawAmount)
       .accounts({
         pool: poolAccount,
         assetInfo: usdcAssetInfo,
-        userPosition: userUsdcPosition,
         mint: usdcMint,
+        shareMint: usdcShareMint,
+        userShareAccount: userUsdcShareAccount,
         userTokenAccount: userUsdcAccount,
         poolTokenAccount: poolUsdcAccount,
         user: user.publicKey,
@@ -354,10 +378,14 @@ describe("Cross-Chain Lending Pool", () => {
       .signers([user])
       .rpc();
 
-    // Verify user position updated
-    const position = await program.account.userPosition.fetch(userUsdcPosition) as UserPosition;
-    const expectedBalance = new BN(500 * Math.pow(10, USDC_DECIMALS)); // 1000 - 500 = 500
-    expect(position.collateralBalance.toString()).to.equal(expectedBalance.toString());
+    // USDC has no borrows so the unlocked shares still redeem one-to-one and are burned
+    const shareAccountInfo = await getAccount(provider.connection, userUsdcShareAccount);
+    const expectedBalance = new BN(200 * Math.pow(10, USDC_DECIMALS)); // 400 - 200 = 200
+    expect(shareAccountInfo.amount.toString()).to.equal(expectedBalance.toString());
+
+    // Shares locked as collateral are untouched
+    const obligation = await program.account.obligation.fetch(userObligation) as Obligation;
+    expect(obligation.deposits[0].shares.toString()).to.equal(new BN(600 * Math.pow(10, USDC_DECIMALS)).toString());
   });
 
   it("Pause and unpause protocol", async () => {
@@ -432,7 +460,7 @@ describe("Cross-Chain Lending Pool", () => {
   it("Handle liquidation", async () => {
     const debtAmount = new BN(100 * Math.pow(10, WETH_DECIMALS));
 
-    const borrowerPosition = userWethPosition;
+    const borrowerObligation = userObligation;
     const borrower = user.publicKey;
     const debtMint = wethMint;
     const collateralMint = usdcMint;
@@ -469,7 +497,9 @@ describe("Cross-Chain Lending Pool", () => {
         .liquidate(debtAmount)
         .accounts({
           pool: poolAccount,
-          borrowerPosition: borrowerPosition,
+          obligation: borrowerObligation,
+          debtAssetInfo: wethAssetInfo,
+          collateralAssetInfo: usdcAssetInfo,
           borrower: borrower,
           debtMint: debtMint,
           collateralMint: collateralMint,
@@ -477,8 +507,13 @@ describe("Cross-Chain Lending Pool", () => {
           liquidatorCollateralAccount: liquidatorCollateralAccount,
           poolDebtAccount: poolWethAccount,
           poolCollateralAccount: poolUsdcAccount,
+          collateralShareMint: usdcShareMint,
+          collateralEscrow: usdcCollateralEscrow,
           debtPriceFeed: mockPriceFeed,
           collateralPriceFeed: mockPriceFeed,
+          debtSecondaryPriceFeed: null,
+          collateralSecondaryPriceFeed: null,
+          emodeCategory: null,
           liquidator: liquidator.publicKey,
           tokenProgram: TOKEN_PROGRAM_ID,
         })
@@ -500,13 +535,13 @@ describe("Cross-Chain Lending Pool", () => {
         .accounts({
           pool: poolAccount,
           assetInfo: usdcAssetInfo,
-          userPosition: userUsdcPosition,
           mint: usdcMint,
+          shareMint: usdcShareMint,
+          userShareAccount: userUsdcShareAccount,
           userTokenAccount: userUsdcAccount,
           poolTokenAccount: poolUsdcAccount,
           user: user.publicKey,
           tokenProgram: TOKEN_PROGRAM_ID,
-          systemProgram: SystemProgram.programId,
         })
         .signers([user])
         .rpc();
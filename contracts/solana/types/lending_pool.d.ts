@@ -19,16 +19,36 @@ export interface AssetInfo {
   canBeBorrowed: boolean;
   totalDeposits: BN;
   totalBorrows: BN;
+  totalShares: BN;
+  shareMint: PublicKey;
   ltv: BN;
   liquidationThreshold: BN;
+  liquidationBonus: BN;
+  closeFactor: BN;
+  cumulativeBorrowIndex: BN;
   priceFeed: PublicKey;
 }
 
-export interface UserPosition {
-  user: PublicKey;
+export interface ObligationCollateral {
   mint: PublicKey;
-  collateralBalance: BN;
-  borrowBalance: BN;
+  shares: BN;
+  marketValueUsd: BN;
+}
+
+export interface ObligationLiquidity {
+  mint: PublicKey;
+  scaledBorrowBalance: BN;
+  isolatedDebtUsd: BN;
+  marketValueUsd: BN;
+}
+
+export interface Obligation {
+  owner: PublicKey;
+  deposits: ObligationCollateral[];
+  borrows: ObligationLiquidity[];
+  isolatedMint: PublicKey | null;
+  emodeCategory: number;
+  healthFactor: BN;
   lastUpdateSlot: BN;
 }
 
@@ -41,8 +61,8 @@ export interface LendingPoolProgram {
     assetInfo: {
       fetch: (address: PublicKey) => Promise<AssetInfo>;
     };
-    userPosition: {
-      fetch: (address: PublicKey) => Promise<UserPosition>;
+    obligation: {
+      fetch: (address: PublicKey) => Promise<Obligation>;
     };
   };
   methods: any;